@@ -1,53 +1,642 @@
-use serde::Deserialize;
-use envy;
+use serde::{Deserialize, Deserializer, Serialize};
 use color_eyre::Result;
-use tracing::{info};
-use tracing_subscriber::EnvFilter;
+use tracing::{error, info};
+use ::config::{Config as ConfigLayers, Environment, File, Value as ConfigValue};
+use std::collections::BTreeMap;
+use crate::audit::REDACTED;
+use crate::db_credentials::DbCredentials;
+use std::sync::Arc;
+use crate::listener::{self, ListenerKind};
+use crate::queries::get_statements;
+use tracing_subscriber::{filter::LevelFilter, prelude::*, EnvFilter};
+use color_eyre::eyre::eyre;
+use crate::telemetry::{init_tracer_provider, TelemetrySettings, EXPORTER_NONE};
 
 // use std::{fs::File, io::BufReader};
 // use rustls::{Certificate, PrivateKey, ServerConfig};
 // use rustls_pemfile::{certs, pkcs8_private_keys};
 
-use openssl::ssl::{SslAcceptor, SslFiletype, SslMethod, SslAcceptorBuilder, SslVerifyMode, SslContextBuilder};
+use openssl::ssl::{SslAcceptor, SslFiletype, SslMethod, SslAcceptorBuilder, SslVerifyMode, SslContext, SslContextBuilder, SslVersion};
+use openssl::x509::X509Name;
+use anyhow::{anyhow, Context};
+use std::net::IpAddr;
+use scylla::transport::Compression;
+use scylla::transport::session::PoolSize;
+use std::num::NonZeroUsize;
+use std::time::Duration;
 
-#[derive(Debug, Deserialize, Clone, Default)]
+pub const DEFAULT_CLUSTER: &str = "default";
+pub const LOAD_BALANCING_DC_AWARE: &str = "dc_aware";
+pub const LOAD_BALANCING_ROUND_ROBIN: &str = "round_robin";
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
 pub struct Config {
+    #[serde(default = "default_host")]
     pub host: String,
+    #[serde(default = "default_port")]
     pub port: i32,
+    #[serde(default, deserialize_with = "optional_string_list")]
+    pub listeners: Option<Vec<String>>,
+    pub workers: Option<usize>,
     pub region: String,
+    #[serde(default = "default_rust_log")]
     pub rust_log: String,
+    #[serde(default)]
     pub db_node0: String,
+    #[serde(default)]
     pub db_node1: String,
+    #[serde(default)]
     pub db_node2: String,
+    #[serde(default, deserialize_with = "optional_string_list")]
+    pub db_contact_points: Option<Vec<String>>,
+    #[serde(default)]
     pub db_user: String,
+    #[serde(default)]
     pub db_password: String,
+    pub db_user_file: Option<String>,
+    pub db_password_file: Option<String>,
+    #[serde(default = "default_db_credentials_poll_seconds")]
+    pub db_credentials_poll_seconds: u64,
+    /// `db_*` settings of the other clusters by name, on top of the ones of the `default` cluster.
+    #[serde(default)]
+    pub clusters: BTreeMap<String, BTreeMap<String, serde_json::Value>>,
+    /// Effective configuration of each cluster of `clusters`, built by [`Config::load`].
+    #[serde(skip)]
+    pub cluster_configs: BTreeMap<String, Config>,
     pub db_dc: String,
     pub parallel_files: usize,
     pub db_parallelism: usize,
     pub payload_max_size: usize,
+    #[serde(default = "default_db_compression")]
+    pub db_compression: String,
+    #[serde(default)]
+    pub db_shard_aware_port: bool,
+    pub db_pool_size_per_shard: Option<usize>,
+    pub db_pool_size_per_host: Option<usize>,
+    #[serde(default = "default_db_connection_timeout_ms")]
+    pub db_connection_timeout_ms: u64,
+    pub db_keepalive_interval_ms: Option<u64>,
+    #[serde(default = "default_db_load_balancing")]
+    pub db_load_balancing: String,
+    #[serde(default = "default_db_token_aware")]
+    pub db_token_aware: bool,
+    #[serde(default = "default_db_include_remote_nodes")]
+    pub db_include_remote_nodes: bool,
+    #[serde(default = "default_db_tls_enabled")]
+    pub db_tls_enabled: bool,
+    #[serde(default = "default_db_tls_ca_file")]
+    pub db_tls_ca_file: String,
+    pub db_tls_cert_file: Option<String>,
+    pub db_tls_key_file: Option<String>,
+    pub db_tls_hostname: Option<String>,
+    pub db_tls_min_version: Option<String>,
+    #[serde(default = "default_compression_enabled")]
+    pub compression_enabled: bool,
+    #[serde(default = "default_compression_min_size")]
+    pub compression_min_size: usize,
+    pub api_keys_file: Option<String>,
+    pub jwt_jwks_file: Option<String>,
+    pub jwt_public_key_file: Option<String>,
+    pub jwt_issuer: Option<String>,
+    pub jwt_audience: Option<String>,
+    #[serde(default = "default_jwt_algorithms", deserialize_with = "string_list")]
+    pub jwt_algorithms: Vec<String>,
+    pub jwt_roles_file: Option<String>,
+    #[serde(default = "default_jwt_leeway_seconds")]
+    pub jwt_leeway_seconds: u64,
+    #[serde(default = "default_tls_cert_file")]
+    pub tls_cert_file: String,
+    #[serde(default = "default_tls_key_file")]
+    pub tls_key_file: String,
+    pub tls_client_ca_file: Option<String>,
+    #[serde(default = "default_tls_reload_poll_seconds")]
+    pub tls_reload_poll_seconds: u64,
+    #[serde(default)]
+    pub tls_client_cert_required: bool,
+    pub client_certificates_file: Option<String>,
+    #[serde(default)]
+    pub admin_allow_anonymous: bool,
+    #[serde(default)]
+    pub anonymous_allow_trace: bool,
+    #[serde(default)]
+    pub direct_statement_deny_ddl: bool,
+    #[serde(default)]
+    pub direct_statement_deny_permission_statements: bool,
+    #[serde(default)]
+    pub direct_statement_deny_allow_filtering: bool,
+    #[serde(default)]
+    pub direct_statement_require_limit: bool,
+    #[serde(default, deserialize_with = "optional_string_list")]
+    pub direct_statement_allowed_keyspaces: Option<Vec<String>>,
+    #[serde(default)]
+    pub read_only: bool,
+    pub read_only_flag_file: Option<String>,
+    #[serde(default = "default_read_only_flag_poll_seconds")]
+    pub read_only_flag_poll_seconds: u64,
+    pub rate_limit_global_per_second: Option<f64>,
+    pub rate_limit_global_burst: Option<f64>,
+    pub rate_limit_identity_per_second: Option<f64>,
+    pub rate_limit_identity_burst: Option<f64>,
+    pub audit_file: Option<String>,
+    #[serde(default = "default_audit_file_max_size_mb")]
+    pub audit_file_max_size_mb: u64,
+    #[serde(default = "default_audit_file_max_files")]
+    pub audit_file_max_files: usize,
+    #[serde(default = "default_audit_queue_size")]
+    pub audit_queue_size: usize,
+    #[serde(default)]
+    pub audit_log: bool,
+    #[serde(default = "default_log_redact_headers", deserialize_with = "string_list")]
+    pub log_redact_headers: Vec<String>,
+    #[serde(default, deserialize_with = "string_list")]
+    pub log_redact_fields: Vec<String>,
+    pub metrics_port: Option<u16>,
+    pub slow_query_threshold_ms: Option<u64>,
+    #[serde(default = "default_slow_query_buffer_size")]
+    pub slow_query_buffer_size: usize,
+    #[serde(default = "default_health_cache_ms")]
+    pub health_cache_ms: u64,
+    #[serde(default = "default_health_query_timeout_ms")]
+    pub health_query_timeout_ms: u64,
+    #[serde(default = "default_shutdown_grace_period_seconds")]
+    pub shutdown_grace_period_seconds: u64,
+    #[serde(default = "default_drain_readiness_delay_seconds")]
+    pub drain_readiness_delay_seconds: u64,
+    #[serde(default = "default_otel_exporter")]
+    pub otel_exporter: String,
+    pub otel_endpoint: Option<String>,
+    pub otel_file: Option<String>,
+    #[serde(default = "default_otel_service_name")]
+    pub otel_service_name: String,
+    #[serde(default = "default_otel_sample_ratio")]
+    pub otel_sample_ratio: f64,
+}
+
+fn default_host() -> String {
+    "0.0.0.0".to_string()
+}
+
+fn default_port() -> i32 {
+    443
+}
+
+fn default_rust_log() -> String {
+    "info".to_string()
+}
+
+fn default_db_credentials_poll_seconds() -> u64 {
+    30
+}
+
+fn default_db_compression() -> String {
+    "lz4".to_string()
+}
+
+fn default_db_connection_timeout_ms() -> u64 {
+    5000
+}
+
+fn default_db_load_balancing() -> String {
+    LOAD_BALANCING_DC_AWARE.to_string()
+}
+
+fn default_db_token_aware() -> bool {
+    true
+}
+
+fn default_db_include_remote_nodes() -> bool {
+    true
+}
+
+fn default_db_tls_enabled() -> bool {
+    true
+}
+
+fn default_db_tls_ca_file() -> String {
+    "scylla_cert.crt".to_string()
+}
+
+fn default_compression_enabled() -> bool {
+    true
+}
+
+fn default_compression_min_size() -> usize {
+    1024
+}
+
+pub(crate) fn default_jwt_algorithms() -> Vec<String> {
+    vec!["RS256".to_string(), "ES256".to_string()]
+}
+
+fn default_jwt_leeway_seconds() -> u64 {
+    30
+}
+
+fn default_read_only_flag_poll_seconds() -> u64 {
+    5
+}
+
+fn default_audit_file_max_size_mb() -> u64 {
+    100
+}
+
+fn default_audit_file_max_files() -> usize {
+    5
 }
 
-fn init_tracer() {
+fn default_audit_queue_size() -> usize {
+    10000
+}
+
+fn default_log_redact_headers() -> Vec<String> {
+    ["authorization", "proxy-authorization", "cookie", "set-cookie", "x-api-key"]
+        .iter()
+        .map(|h| h.to_string())
+        .collect()
+}
+
+fn default_slow_query_buffer_size() -> usize {
+    100
+}
+
+fn default_health_cache_ms() -> u64 {
+    1000
+}
+
+fn default_health_query_timeout_ms() -> u64 {
+    1000
+}
+
+fn default_otel_exporter() -> String {
+    EXPORTER_NONE.to_string()
+}
+
+fn default_otel_service_name() -> String {
+    "yaw_dbproxy".to_string()
+}
+
+fn default_otel_sample_ratio() -> f64 {
+    1.0
+}
+
+fn default_shutdown_grace_period_seconds() -> u64 {
+    30
+}
+
+fn default_drain_readiness_delay_seconds() -> u64 {
+    5
+}
+
+fn default_tls_reload_poll_seconds() -> u64 {
+    30
+}
+
+fn default_tls_cert_file() -> String {
+    "cert.pem".to_string()
+}
+
+fn default_tls_key_file() -> String {
+    "key.pem".to_string()
+}
+
+/// `RUST_LOG` only filters the log output, spans are exported from the `info` level up
+/// whatever the log level is.
+pub fn init_tracer(config: &Config) -> Result<()> {
     #[cfg(debug_assertions)]
-    let tracer = tracing_subscriber::fmt();
+    let log_layer = tracing_subscriber::fmt::layer();
     #[cfg(not(debug_assertions))]
-    let tracer = tracing_subscriber::fmt().json();
-    tracer.with_env_filter(EnvFilter::from_default_env()).init();
+    let log_layer = tracing_subscriber::fmt::layer().json();
+    let otel_layer = init_tracer_provider(&TelemetrySettings {
+        exporter: config.otel_exporter.clone(),
+        endpoint: config.otel_endpoint.clone(),
+        file: config.otel_file.clone(),
+        service_name: config.otel_service_name.clone(),
+        sample_ratio: config.otel_sample_ratio,
+    })
+    .map_err(|e| eyre!("^^^ OPENTELEMETRY CONFIG ERROR {}", e))?
+    .map(|tracer| tracing_opentelemetry::layer().with_tracer(tracer).with_filter(LevelFilter::INFO));
+    tracing_subscriber::registry()
+        .with(log_layer.with_filter(EnvFilter::try_new(&config.rust_log).map_err(|e| eyre!("^^^ RUST_LOG CONFIG ERROR {}", e))?))
+        .with(otel_layer)
+        .init();
+    Ok(())
+}
+
+/// Lists are accepted as arrays in the configuration file and comma separated in env vars and flags.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StringList {
+    List(Vec<String>),
+    Joined(String),
+}
+
+impl From<StringList> for Vec<String> {
+    fn from(list: StringList) -> Vec<String> {
+        match list {
+            StringList::List(v) => v,
+            StringList::Joined(v) => v.split(',').map(|s| s.trim().to_string()).filter(|s| !s.is_empty()).collect()
+        }
+    }
+}
+
+fn string_list<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    Ok(StringList::deserialize(deserializer)?.into())
+}
+
+fn optional_string_list<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Vec<String>>, D::Error> {
+    Ok(Option::<StringList>::deserialize(deserializer)?.map(|l| l.into()))
+}
+
+/// Command line of the proxy: `--config <file>`, `--print-config`, and any setting as
+/// `--<name> <value>` or `--<name>=<value>`, e.g. `--db-dc dc1` for `DB_DC`.
+#[derive(Debug, Default)]
+pub struct CliArgs {
+    pub config_file: Option<String>,
+    pub print_config: bool,
+    pub overrides: Vec<(String, String)>,
+}
+
+impl CliArgs {
+    /// `args` without the program name.
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<CliArgs, anyhow::Error> {
+        let mut cli = CliArgs::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let flag = match arg.strip_prefix("--") {
+                Some(v) => v,
+                None => return Err(anyhow!("unexpected argument {}, settings are given as --<name> <value>", arg))
+            };
+            if flag == "print-config" {
+                cli.print_config = true;
+                continue
+            }
+            let (name, value) = match flag.split_once('=') {
+                Some((name, value)) => (name.to_string(), value.to_string()),
+                None => match args.next() {
+                    Some(value) => (flag.to_string(), value),
+                    None => return Err(anyhow!("--{} needs a value", flag))
+                }
+            };
+            if name == "config" {
+                cli.config_file = Some(value);
+            } else {
+                cli.overrides.push((name.replace('-', "_").to_lowercase(), value));
+            }
+        }
+        Ok(cli)
+    }
+}
+
+/// Settings whose value is never printed.
+fn is_secret(i_name: &str) -> bool {
+    ["password", "secret", "token"].iter().any(|s| i_name.contains(s))
+}
+
+/// Also goes through the settings of `clusters`.
+fn redact_secrets(settings: &mut serde_json::Value) {
+    if let Some(settings) = settings.as_object_mut() {
+        for (name, value) in settings.iter_mut() {
+            if value.is_object() {
+                redact_secrets(value);
+            } else if is_secret(name) && !value.is_null() {
+                *value = serde_json::json!(REDACTED);
+            }
+        }
+    }
+}
+
+/// Settings that say where a cluster is and who connects to it. A named cluster never takes them
+/// from the `default` cluster, the ones it doesn't set keep their built-in default.
+fn reset_cluster_own_settings(layers: &mut ConfigLayers) -> Result<(), anyhow::Error> {
+    for name in ["db_node0", "db_node1", "db_node2", "db_dc", "db_user", "db_password"] {
+        layers.set(name, "")?;
+    }
+    for name in ["db_contact_points", "db_user_file", "db_password_file", "db_tls_cert_file", "db_tls_key_file", "db_tls_hostname"] {
+        layers.set(name, None::<String>)?;
+    }
+    layers.set("db_tls_ca_file", default_db_tls_ca_file())?;
+    Ok(())
 }
 
 impl Config {
 
-    pub fn from_env() -> Result<Config> {
+    /// Layers, each one overriding the previous: the defaults, the configuration file
+    /// (`--config` or `CONFIG_FILE`, TOML, YAML or JSON), a `.env` file, the env vars, the command line flags.
+    /// A missing required setting is an error instead of an empty value.
+    pub fn load(cli: &CliArgs) -> Result<Config, anyhow::Error> {
 
-        init_tracer();
-        info!("Loading configuration");
-        let ret_env = match envy::from_env::<Config>() {
-            Ok(environment) => environment,
-            Err(_e) => return Ok(Config::default()),
-        };
-        Ok(ret_env)
+        dotenv::dotenv().ok();
+
+        let mut layers = ConfigLayers::default();
+        if let Some(path) = cli.config_file.clone().or_else(|| std::env::var("CONFIG_FILE").ok()) {
+            layers
+                .merge(File::with_name(&path))
+                .with_context(|| format!("configuration file {} can't be read", path))?;
+        }
+        layers.merge(Environment::new().separator("__").ignore_empty(true))?;
+        for (name, value) in &cli.overrides {
+            layers.set(name, value.clone())?;
+        }
+
+        let mut config = layers.clone().try_into::<Config>().map_err(|e| anyhow!("invalid configuration: {}", e))?;
+
+        for (cluster, settings) in &config.clusters {
+            if cluster == DEFAULT_CLUSTER || cluster.is_empty() {
+                return Err(anyhow!("clusters: {} is not a valid cluster name", cluster))
+            }
+            if !["db_contact_points", "db_node0", "db_node1", "db_node2"].iter().any(|k| settings.contains_key(*k)) {
+                return Err(anyhow!("clusters.{}: db_contact_points or db_node0..2 must be set, a cluster never uses the contact points of the default one", cluster))
+            }
+            let mut cluster_layers = layers.clone();
+            reset_cluster_own_settings(&mut cluster_layers)?;
+            for name in settings.keys() {
+                if !name.starts_with("db_") {
+                    return Err(anyhow!("clusters.{}.{}: only db_* settings can be set per cluster", cluster, name))
+                }
+                let value: ConfigValue = layers.get(&format!("clusters.{}.{}", cluster, name))?;
+                cluster_layers.set(name, value)?;
+            }
+            let cluster_config = cluster_layers
+                .try_into::<Config>()
+                .map_err(|e| anyhow!("invalid configuration of cluster {}: {}", cluster, e))?;
+            config.cluster_configs.insert(cluster.clone(), cluster_config);
+        }
+
+        Ok(config)
+    }
+
+    /// Name and configuration of every cluster, the `default` one first.
+    pub fn all_clusters(&self) -> Vec<(&str, &Config)> {
+        let mut ret = vec![(DEFAULT_CLUSTER, self)];
+        ret.extend(self.cluster_configs.iter().map(|(name, config)| (name.as_str(), config)));
+        ret
+    }
+
+    /// The effective configuration, for `--print-config`. `cluster_configs` has the resolved
+    /// configuration of each named cluster.
+    pub fn redacted(&self) -> serde_json::Value {
+        let mut rj = serde_json::to_value(self).unwrap_or_default();
+        if !self.cluster_configs.is_empty() {
+            rj["cluster_configs"] = self.cluster_configs
+                .iter()
+                .map(|(name, config)| (name.clone(), serde_json::to_value(config).unwrap_or_default()))
+                .collect::<serde_json::Map<String, serde_json::Value>>()
+                .into();
+        }
+        redact_secrets(&mut rj);
+        rj
+    }
+}
+
+/// Runs every startup check and logs one line each, so all the configuration problems
+/// show up at once. Returns false when one of them failed.
+pub fn log_validation_report(config: &Config) -> bool {
+    let mut checks: Vec<(String, Result<(), anyhow::Error>)> = vec![];
+
+    let listeners = listener::from_config(config);
+    let has_https = match &listeners {
+        Ok(v) => v.iter().any(|l| l.kind == ListenerKind::Https),
+        Err(_) => false
+    };
+    checks.push(("listeners".to_string(), listeners.map(|_| ())));
+    checks.push(("workers".to_string(), listener::workers(config).map(|_| ())));
+    if has_https {
+        checks.push(("https".to_string(), load_openssl_config(config).map(|_| ()).context("TLS_CERT_FILE, TLS_KEY_FILE or TLS_CLIENT_CA_FILE")));
+    }
+    for (cluster, cluster_config) in config.all_clusters() {
+        checks.push((format!("scylla_driver[{}]", cluster), driver_settings(cluster_config).map(|_| ())));
+    }
+    checks.push(("statement_clusters".to_string(), check_statement_clusters(config)));
+
+    let files = [
+        ("API_KEYS_FILE", &config.api_keys_file),
+        ("JWT_JWKS_FILE", &config.jwt_jwks_file),
+        ("JWT_PUBLIC_KEY_FILE", &config.jwt_public_key_file),
+        ("JWT_ROLES_FILE", &config.jwt_roles_file),
+        ("CLIENT_CERTIFICATES_FILE", &config.client_certificates_file),
+    ];
+    for (name, path) in files {
+        if let Some(path) = path {
+            checks.push((name.to_string(), std::fs::metadata(path).map(|_| ()).with_context(|| format!("{} can't be read", path))));
+        }
+    }
+
+    let mut passed = true;
+    for (name, result) in checks {
+        match result {
+            Ok(()) => info!("Configuration check {}: ok", name),
+            Err(e) => {
+                error!("Configuration check {}: {:#}", name, e);
+                passed = false;
+            }
+        }
+    }
+    passed
+}
+
+/// Every statement of **queries.rs** runs on a configured cluster.
+fn check_statement_clusters(config: &Config) -> Result<(), anyhow::Error> {
+    let clusters: Vec<&str> = config.all_clusters().iter().map(|(name, _)| *name).collect();
+    let statements = get_statements();
+    let unknown: Vec<String> = statements
+        .as_object()
+        .into_iter()
+        .flatten()
+        .filter_map(|(statement_id, statement)| {
+            let cluster = statement.get("cluster").and_then(|c| c.as_str()).unwrap_or(DEFAULT_CLUSTER);
+            if clusters.contains(&cluster) { None } else { Some(format!("{} targets {}", statement_id, cluster)) }
+        })
+        .collect();
+    if unknown.is_empty() {
+        Ok(())
+    } else {
+        Err(anyhow!("statements on a cluster which is not configured: {}", unknown.join(", ")))
+    }
+}
+
+/// Session options of the Scylla driver, checked by [`driver_settings`].
+#[derive(Debug, Clone)]
+pub struct DriverSettings {
+    pub contact_points: Vec<String>,
+    pub credentials: Arc<DbCredentials>,
+    pub datacenter: String,
+    pub parallelism: usize,
+    pub compression: Option<Compression>,
+    pub shard_aware_port: bool,
+    pub pool_size: PoolSize,
+    pub connection_timeout: Duration,
+    pub keepalive_interval: Option<Duration>,
+    pub load_balancing: String,
+    pub token_aware: bool,
+    pub include_remote_nodes: bool,
+    pub ssl_context: Option<SslContext>,
+}
 
+/// `DB_CONTACT_POINTS` when set, `DB_NODE0`, `DB_NODE1` and `DB_NODE2` otherwise.
+pub fn driver_settings(config: &Config) -> Result<DriverSettings, anyhow::Error> {
+
+    let contact_points: Vec<String> = match &config.db_contact_points {
+        Some(v) => v.iter().map(|c| c.trim().to_string()).filter(|c| !c.is_empty()).collect(),
+        None => [&config.db_node0, &config.db_node1, &config.db_node2]
+            .iter()
+            .filter(|c| !c.is_empty())
+            .map(|c| c.to_string())
+            .collect()
+    };
+    if contact_points.is_empty() {
+        return Err(anyhow!("no contact point, set DB_CONTACT_POINTS"))
+    }
+
+    let compression = match config.db_compression.to_lowercase().as_str() {
+        "lz4" => Some(Compression::Lz4),
+        "snappy" => Some(Compression::Snappy),
+        "none" => None,
+        other => return Err(anyhow!("DB_COMPRESSION {} is not one of lz4, snappy, none", other))
+    };
+
+    let pool_size = match (config.db_pool_size_per_shard, config.db_pool_size_per_host) {
+        (Some(_), Some(_)) => return Err(anyhow!("DB_POOL_SIZE_PER_SHARD and DB_POOL_SIZE_PER_HOST can't be both set")),
+        (Some(n), None) => PoolSize::PerShard(NonZeroUsize::new(n).ok_or_else(|| anyhow!("DB_POOL_SIZE_PER_SHARD must be at least 1"))?),
+        (None, Some(n)) => PoolSize::PerHost(NonZeroUsize::new(n).ok_or_else(|| anyhow!("DB_POOL_SIZE_PER_HOST must be at least 1"))?),
+        (None, None) => PoolSize::default()
+    };
+
+    if config.db_connection_timeout_ms == 0 {
+        return Err(anyhow!("DB_CONNECTION_TIMEOUT_MS must be greater than 0"))
     }
+    if config.db_keepalive_interval_ms == Some(0) {
+        return Err(anyhow!("DB_KEEPALIVE_INTERVAL_MS must be greater than 0"))
+    }
+
+    match config.db_load_balancing.as_str() {
+        LOAD_BALANCING_DC_AWARE => {
+            if config.db_dc.is_empty() {
+                return Err(anyhow!("DB_LOAD_BALANCING dc_aware needs DB_DC"))
+            }
+        },
+        LOAD_BALANCING_ROUND_ROBIN => {
+            if !config.db_include_remote_nodes {
+                return Err(anyhow!("DB_INCLUDE_REMOTE_NODES=false needs DB_LOAD_BALANCING dc_aware"))
+            }
+        },
+        other => return Err(anyhow!("DB_LOAD_BALANCING {} is not one of {}, {}", other, LOAD_BALANCING_DC_AWARE, LOAD_BALANCING_ROUND_ROBIN))
+    }
+
+    Ok(DriverSettings {
+        contact_points,
+        credentials: DbCredentials::load(&config.db_user, &config.db_password, config.db_user_file.clone(), config.db_password_file.clone())?,
+        datacenter: config.db_dc.clone(),
+        parallelism: config.db_parallelism,
+        compression,
+        shard_aware_port: config.db_shard_aware_port,
+        pool_size,
+        connection_timeout: Duration::from_millis(config.db_connection_timeout_ms),
+        keepalive_interval: config.db_keepalive_interval_ms.map(Duration::from_millis),
+        load_balancing: config.db_load_balancing.clone(),
+        token_aware: config.db_token_aware,
+        include_remote_nodes: config.db_include_remote_nodes,
+        ssl_context: load_ssl_scylla_config(config)?,
+    })
 }
 
 // pub fn load_rustls_config() -> rustls::ServerConfig {
@@ -78,24 +667,79 @@ impl Config {
 //     config.with_single_cert(cert_chain, keys.remove(0)).unwrap()
 // }
 
-pub fn load_openssl_config() -> SslAcceptorBuilder {
+pub fn load_openssl_config(config: &Config) -> Result<SslAcceptorBuilder, anyhow::Error> {
 
-    let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls()).unwrap();
-    builder
-        .set_private_key_file("key.pem", SslFiletype::PEM)
-        .unwrap();
-    builder.set_certificate_chain_file("cert.pem").unwrap();
+    let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls())?;
+    builder.set_private_key_file(&config.tls_key_file, SslFiletype::PEM)?;
+    builder.set_certificate_chain_file(&config.tls_cert_file)?;
+    builder.check_private_key()?;
 
-    builder
+    if let Some(ca_file) = &config.tls_client_ca_file {
+        builder.set_ca_file(ca_file)?;
+        let client_names = X509Name::load_client_ca_file(ca_file)?;
+        builder.set_client_ca_list(client_names);
+        if config.tls_client_cert_required {
+            builder.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+        } else {
+            builder.set_verify(SslVerifyMode::PEER);
+        }
+    } else if config.tls_client_cert_required {
+        return Err(anyhow::anyhow!("tls_client_cert_required needs tls_client_ca_file"));
+    }
+
+    Ok(builder)
 }
 
-pub fn load_ssl_scylla_config() -> Result<SslContextBuilder, anyhow::Error> {
+/// TLS towards the Scylla nodes, `None` when `DB_TLS_ENABLED` is false.
+/// The node certificates are always checked against `DB_TLS_CA_FILE`.
+pub fn load_ssl_scylla_config(config: &Config) -> Result<Option<SslContext>, anyhow::Error> {
+
+    if !config.db_tls_enabled {
+        return Ok(None)
+    }
 
     let mut context_builder = SslContextBuilder::new(SslMethod::tls())?;
-    context_builder.set_ca_file("scylla_cert.crt")?;
+    context_builder
+        .set_ca_file(&config.db_tls_ca_file)
+        .with_context(|| format!("DB_TLS_CA_FILE {} can't be loaded", config.db_tls_ca_file))?;
     context_builder.set_verify(SslVerifyMode::PEER);
 
-    Ok(context_builder)
+    match (&config.db_tls_cert_file, &config.db_tls_key_file) {
+        (Some(cert_file), Some(key_file)) => {
+            context_builder
+                .set_certificate_chain_file(cert_file)
+                .with_context(|| format!("DB_TLS_CERT_FILE {} can't be loaded", cert_file))?;
+            context_builder
+                .set_private_key_file(key_file, SslFiletype::PEM)
+                .with_context(|| format!("DB_TLS_KEY_FILE {} can't be loaded", key_file))?;
+            context_builder
+                .check_private_key()
+                .with_context(|| format!("DB_TLS_KEY_FILE {} doesn't match DB_TLS_CERT_FILE {}", key_file, cert_file))?;
+        },
+        (None, None) => (),
+        _ => return Err(anyhow!("DB_TLS_CERT_FILE and DB_TLS_KEY_FILE must be set together"))
+    }
+
+    if let Some(hostname) = &config.db_tls_hostname {
+        let verify_param = context_builder.verify_param_mut();
+        match hostname.parse::<IpAddr>() {
+            Ok(ip) => verify_param.set_ip(ip),
+            Err(_) => verify_param.set_host(hostname)
+        }.with_context(|| format!("DB_TLS_HOSTNAME {} is not usable", hostname))?;
+    }
+
+    if let Some(min_version) = &config.db_tls_min_version {
+        let version = match min_version.as_str() {
+            "1.0" => SslVersion::TLS1,
+            "1.1" => SslVersion::TLS1_1,
+            "1.2" => SslVersion::TLS1_2,
+            "1.3" => SslVersion::TLS1_3,
+            other => return Err(anyhow!("DB_TLS_MIN_VERSION {} is not one of 1.0, 1.1, 1.2, 1.3", other))
+        };
+        context_builder.set_min_proto_version(Some(version))?;
+    }
+
+    Ok(Some(context_builder.build()))
 }
 
 // use scylla::{Session, SessionBuilder};
@@ -110,4 +754,97 @@ pub fn load_ssl_scylla_config() -> Result<SslContextBuilder, anyhow::Error> {
 //     .known_node("127.0.0.1:9142") // The the port is now 9142
 //     .ssl_context(Some(context_builder.build()))
 //     .build()
-//     .await?;
\ No newline at end of file
+//     .await?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(i_args: &[&str]) -> Vec<String> {
+        i_args.iter().map(|a| a.to_string()).collect()
+    }
+
+    fn config_file(i_name: &str, i_content: &str) -> String {
+        let path = std::env::temp_dir().join(format!("config_test_{}_{}.toml", i_name, std::process::id()));
+        std::fs::write(&path, i_content).unwrap();
+        path.to_string_lossy().to_string()
+    }
+
+    #[test]
+    fn cli_args() {
+        let cli = CliArgs::parse(args(&["--config", "proxy.toml", "--db-dc", "dc2", "--PORT=8444", "--print-config", "--audit-file=a=b"])).unwrap();
+        assert_eq!(cli.config_file.as_deref(), Some("proxy.toml"));
+        assert!(cli.print_config);
+        assert_eq!(cli.overrides, vec![
+            ("db_dc".to_string(), "dc2".to_string()),
+            ("port".to_string(), "8444".to_string()),
+            ("audit_file".to_string(), "a=b".to_string()),
+        ]);
+
+        let cli = CliArgs::parse(args(&[])).unwrap();
+        assert!(cli.config_file.is_none());
+        assert!(!cli.print_config);
+        assert!(cli.overrides.is_empty());
+
+        assert!(CliArgs::parse(args(&["db_dc", "dc2"])).is_err());
+        assert!(CliArgs::parse(args(&["--db-dc"])).is_err());
+    }
+
+    // Environment variables are shared by the whole test binary, every test of `Config::load`
+    // stays in this one.
+    #[test]
+    fn load_precedence() {
+        let path = config_file("precedence", r#"
+region = "file"
+db_dc = "file_dc"
+db_user = "file_user"
+parallel_files = 4
+db_parallelism = 8
+payload_max_size = 1024
+
+[clusters.analytics]
+db_node0 = "10.0.2.1"
+db_dc = "analytics_dc"
+db_password = "analytics_password"
+"#);
+        std::env::set_var("DB_USER", "env_user");
+        std::env::set_var("DB_PARALLELISM", "16");
+        let loaded = Config::load(&CliArgs::parse(args(&["--config", &path, "--db-parallelism", "32"])).unwrap());
+        std::env::remove_var("DB_USER");
+        std::env::remove_var("DB_PARALLELISM");
+        let config = loaded.unwrap();
+
+        // file < environment < command line
+        assert_eq!(config.region, "file");
+        assert_eq!(config.db_user, "env_user");
+        assert_eq!(config.db_parallelism, 32);
+        assert_eq!(config.parallel_files, 4);
+        assert_eq!(config.port, default_port());
+
+        // a named cluster takes the shared settings, never the contact points or the credentials
+        let analytics = &config.cluster_configs["analytics"];
+        assert_eq!(analytics.db_dc, "analytics_dc");
+        assert_eq!(analytics.db_node0, "10.0.2.1");
+        assert_eq!(analytics.db_user, "");
+        assert_eq!(analytics.db_parallelism, 32);
+        assert_eq!(config.all_clusters().iter().map(|(name, _)| *name).collect::<Vec<&str>>(), vec![DEFAULT_CLUSTER, "analytics"]);
+
+        // --print-config shows the resolved clusters, without their secrets
+        let printed = config.redacted();
+        assert_eq!(printed["cluster_configs"]["analytics"]["db_dc"], "analytics_dc");
+        assert_eq!(printed["cluster_configs"]["analytics"]["db_password"], REDACTED);
+        assert_eq!(printed["clusters"]["analytics"]["db_password"], REDACTED);
+
+        let path = config_file("no_contact_points", r#"
+region = "file"
+db_dc = "file_dc"
+parallel_files = 4
+db_parallelism = 8
+payload_max_size = 1024
+
+[clusters.analytics]
+db_dc = "analytics_dc"
+"#);
+        assert!(Config::load(&CliArgs::parse(args(&["--config", &path])).unwrap()).is_err());
+        assert!(Config::load(&CliArgs::parse(args(&["--config", "/nonexistent/proxy.toml"])).unwrap()).is_err());
+    }
+}
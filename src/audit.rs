@@ -0,0 +1,297 @@
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{sync_channel, SyncSender, TrySendError};
+use std::thread::JoinHandle;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use anyhow::Result;
+use tracing::{info, warn};
+use crate::auth::Identity;
+
+pub const REDACTED: &str = "***";
+
+/// One record per operation, success or failure.
+#[derive(Debug, Clone, Serialize)]
+pub struct AuditRecord {
+    pub timestamp_ms: u128,
+    pub request_id: String,
+    pub identity: String,
+    pub auth_method: String,
+    pub endpoint: String,
+    pub statement_id: String,
+    pub statement_hash: Option<String>,
+    pub parameters: Vec<serde_json::Value>,
+    pub rows_returned: u64,
+    pub latency_ms: f64,
+    pub outcome: String,
+    pub status_code: u16,
+    pub error: Option<String>,
+}
+
+impl AuditRecord {
+    pub fn new(
+        identity: &Identity,
+        i_endpoint: &str,
+        i_statement_id: &str,
+        i_statement_hash: Option<String>,
+        i_parameters: Vec<serde_json::Value>,
+        i_started: Instant,
+        i_result: &Result<serde_json::Value, serde_json::Value>
+    ) -> AuditRecord {
+        let (outcome, status_code, rows_returned, error) = match i_result {
+            Ok(v) => ("success", 200, v["records_number"].as_u64().unwrap_or(0), None),
+            Err(e) => (
+                "error",
+                e["status_code"].as_u64().unwrap_or(500) as u16,
+                0,
+                e["error_message"].as_str().map(|s| s.to_string())
+            )
+        };
+        AuditRecord {
+            timestamp_ms: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis()).unwrap_or(0),
+            request_id: String::new(),
+            identity: identity.subject.clone(),
+            auth_method: identity.method.clone(),
+            endpoint: i_endpoint.to_string(),
+            statement_id: i_statement_id.to_string(),
+            statement_hash: i_statement_hash,
+            parameters: i_parameters,
+            rows_returned,
+            latency_ms: i_started.elapsed().as_secs_f64() * 1000.0,
+            outcome: outcome.to_string(),
+            status_code,
+            error,
+        }
+    }
+
+    pub fn with_request_id(mut self, request_id: &str) -> AuditRecord {
+        self.request_id = request_id.to_string();
+        self
+    }
+}
+
+/// Where audit records end up. Sinks are called from a dedicated thread, so they can block.
+pub trait AuditSink: Send {
+    fn write(&mut self, record: &AuditRecord) -> Result<(), anyhow::Error>;
+
+    /// Called once the last record is written, at shutdown.
+    fn flush(&mut self) -> Result<(), anyhow::Error> {
+        Ok(())
+    }
+}
+
+/// JSON lines file, rotated when it grows past `max_size` bytes.
+/// `audit.log` becomes `audit.log.1`, `audit.log.1` becomes `audit.log.2` and so on, up to
+/// `max_files` rotated files.
+pub struct FileSink {
+    path: String,
+    max_size: u64,
+    max_files: usize,
+    file: File,
+    size: u64,
+}
+
+impl FileSink {
+    pub fn new(i_path: &str, i_max_size: u64, i_max_files: usize) -> Result<FileSink, anyhow::Error> {
+        let file = OpenOptions::new().create(true).append(true).open(i_path)?;
+        let size = file.metadata()?.len();
+        Ok(FileSink {
+            path: i_path.to_string(),
+            max_size: i_max_size,
+            max_files: i_max_files,
+            file,
+            size,
+        })
+    }
+
+    fn rotate(&mut self) -> Result<(), anyhow::Error> {
+        self.file.flush()?;
+        for idx in (1..self.max_files).rev() {
+            let from = format!("{}.{}", self.path, idx);
+            if std::path::Path::new(&from).exists() {
+                std::fs::rename(&from, format!("{}.{}", self.path, idx + 1))?;
+            }
+        }
+        if self.max_files > 0 {
+            std::fs::rename(&self.path, format!("{}.1", self.path))?;
+        }
+        self.file = OpenOptions::new().create(true).write(true).truncate(true).open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl AuditSink for FileSink {
+    fn write(&mut self, record: &AuditRecord) -> Result<(), anyhow::Error> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        if self.size > 0 && self.size + line.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        self.file.write_all(&line)?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), anyhow::Error> {
+        self.file.flush()?;
+        Ok(self.file.sync_data()?)
+    }
+}
+
+/// Emits each record as a `tracing` event with target `audit`.
+pub struct TracingSink;
+
+impl AuditSink for TracingSink {
+    fn write(&mut self, record: &AuditRecord) -> Result<(), anyhow::Error> {
+        info!(target: "audit", "{}", serde_json::to_string(record)?);
+        Ok(())
+    }
+}
+
+/// Hands records over to a background thread that writes them to every sink, so handlers
+/// never wait on audit I/O.
+/// The queue holds at most `queue_size` records: when the sinks can't keep up, new records are
+/// dropped and counted rather than growing the memory without bound.
+pub struct Auditor {
+    sender: Mutex<Option<SyncSender<AuditRecord>>>,
+    writer: Mutex<Option<JoinHandle<()>>>,
+    dropped: AtomicU64,
+}
+
+impl Auditor {
+    pub fn new(mut sinks: Vec<Box<dyn AuditSink>>, queue_size: usize) -> Auditor {
+        if sinks.is_empty() {
+            return Auditor { sender: Mutex::new(None), writer: Mutex::new(None), dropped: AtomicU64::new(0) }
+        }
+        let (sender, receiver) = sync_channel::<AuditRecord>(queue_size.max(1));
+        let writer = std::thread::Builder::new()
+            .name("audit".to_string())
+            .spawn(move || {
+                for record in receiver {
+                    for sink in sinks.iter_mut() {
+                        if let Err(e) = sink.write(&record) {
+                            warn!("Audit sink error: {}", e);
+                        }
+                    }
+                }
+                for sink in sinks.iter_mut() {
+                    if let Err(e) = sink.flush() {
+                        warn!("Audit sink flush error: {}", e);
+                    }
+                }
+            })
+            .expect("^^^ AUDIT THREAD ERROR");
+        Auditor {
+            sender: Mutex::new(Some(sender)),
+            writer: Mutex::new(Some(writer)),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    pub fn record(&self, record: AuditRecord) {
+        if let Some(sender) = self.sender.lock().unwrap().as_ref() {
+            match sender.try_send(record) {
+                Ok(()) => (),
+                Err(TrySendError::Full(_)) => {
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                },
+                Err(TrySendError::Disconnected(_)) => {
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                    warn!("Audit thread is gone, record dropped");
+                }
+            }
+        }
+    }
+
+    /// Records dropped because the queue was full.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Closes the queue, then waits for the writer to drain it and flush the sinks.
+    /// Records sent afterwards are ignored.
+    pub fn close(&self) {
+        drop(self.sender.lock().unwrap().take());
+        if let Some(writer) = self.writer.lock().unwrap().take() {
+            if writer.join().is_err() {
+                warn!("Audit thread panicked, records may be lost");
+            }
+        }
+    }
+}
+
+/// Client values of a catalog statement, with the positions listed in the catalog `sensitive`
+/// array replaced by [`REDACTED`].
+/// Positions filled by `identity_binding` are skipped, as the client does not send them.
+pub fn redact_parameters(
+    i_attributes: Option<&HashMap<String, serde_json::Value>>,
+    i_query_data: &[serde_json::Value]
+) -> Vec<serde_json::Value> {
+    let attributes = match i_attributes {
+        Some(v) => v,
+        None => return i_query_data.iter().map(|_| serde_json::json!(REDACTED)).collect()
+    };
+    let is_sensitive = |position: usize| attributes
+        .get("sensitive")
+        .and_then(|s| s.as_array())
+        .map(|s| s.iter().any(|p| p.as_str() == Some(&position.to_string())))
+        .unwrap_or(false);
+    let is_bound = |position: usize| attributes
+        .get("identity_binding")
+        .and_then(|b| b.as_object())
+        .map(|b| b.contains_key(&position.to_string()))
+        .unwrap_or(false);
+
+    let mut positions = (0..).filter(|p| !is_bound(*p));
+    i_query_data
+        .iter()
+        .map(|v| match positions.next() {
+            Some(p) if !is_sensitive(p) => v.clone(),
+            _ => serde_json::json!(REDACTED)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn attributes(i_sensitive: serde_json::Value, i_identity_binding: serde_json::Value) -> HashMap<String, serde_json::Value> {
+        HashMap::from([
+            ("sensitive".to_string(), i_sensitive),
+            ("identity_binding".to_string(), i_identity_binding),
+        ])
+    }
+
+    #[test]
+    fn sensitive_positions_are_redacted() {
+        let statement = attributes(json!(["1"]), json!({}));
+        let parameters = redact_parameters(Some(&statement), &[json!("acme"), json!("alice@example.com"), json!(3)]);
+        assert_eq!(parameters, vec![json!("acme"), json!(REDACTED), json!(3)]);
+    }
+
+    #[test]
+    fn bound_positions_are_skipped() {
+        // position 0 comes from the identity, the client values are positions 1 and 2
+        let statement = attributes(json!(["2"]), json!({"0": "claim:tenant"}));
+        let parameters = redact_parameters(Some(&statement), &[json!(42), json!("secret")]);
+        assert_eq!(parameters, vec![json!(42), json!(REDACTED)]);
+    }
+
+    #[test]
+    fn unknown_statement_is_fully_redacted() {
+        let parameters = redact_parameters(None, &[json!("a"), json!(1)]);
+        assert_eq!(parameters, vec![json!(REDACTED), json!(REDACTED)]);
+    }
+
+    #[test]
+    fn missing_catalog_entries_redact_nothing() {
+        let parameters = redact_parameters(Some(&HashMap::new()), &[json!("a"), json!(1)]);
+        assert_eq!(parameters, vec![json!("a"), json!(1)]);
+    }
+}
@@ -0,0 +1,73 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use anyhow::Result;
+use crate::auth::{Identity, Permissions};
+use crate::common::sha256_hex;
+
+pub const API_KEY_HEADER: &str = "x-api-key";
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct ApiKeyEntry {
+    pub name: String,
+    pub key_hash: String,
+    #[serde(flatten)]
+    pub permissions: Permissions,
+    #[serde(default)]
+    pub metadata: HashMap<String, serde_json::Value>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ApiKeyFile {
+    keys: Vec<ApiKeyEntry>,
+}
+
+/// API keys loaded from `api_keys_file`, indexed by the SHA-256 hex digest of the key.
+/// Plain keys are never stored, neither on disk nor in memory.
+pub struct ApiKeyStore {
+    keys: HashMap<String, ApiKeyEntry>,
+}
+
+pub fn hash_api_key(i_key: &str) -> String {
+    sha256_hex(i_key)
+}
+
+impl ApiKeyStore {
+    pub fn from_file(i_path: &str) -> Result<ApiKeyStore, anyhow::Error> {
+        let content = std::fs::read_to_string(i_path)?;
+        let key_file: ApiKeyFile = serde_json::from_str(&content)?;
+
+        let mut keys = HashMap::new();
+        for entry in key_file.keys {
+            let key_hash = entry.key_hash.to_lowercase();
+            if key_hash.len() != 64 || !key_hash.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(anyhow::anyhow!("api key {} has an invalid key_hash, expected a sha256 hex digest", entry.name));
+            }
+            if keys.insert(key_hash, entry.clone()).is_some() {
+                return Err(anyhow::anyhow!("api key {} is defined twice", entry.name));
+            }
+        }
+
+        Ok(ApiKeyStore { keys })
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    pub fn authenticate(&self, i_key: &str) -> Option<Identity> {
+        let entry = self.keys.get(&hash_api_key(i_key))?;
+        Some(Identity {
+            subject: entry.name.clone(),
+            method: "api_key".to_string(),
+            permissions: entry.permissions.clone(),
+            attributes: entry.metadata
+                .iter()
+                .map(|(k, v)| (format!("api_key:{}", k), v.clone()))
+                .collect(),
+        })
+    }
+}
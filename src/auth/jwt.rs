@@ -0,0 +1,294 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::str::FromStr;
+use anyhow::Result;
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use jsonwebtoken::jwk::{AlgorithmParameters, JwkSet};
+use crate::auth::{Identity, Permissions};
+
+#[derive(Debug, Deserialize, Clone)]
+struct RolesFile {
+    #[serde(default = "default_roles_claim")]
+    roles_claim: String,
+    roles: HashMap<String, Permissions>,
+}
+
+fn default_roles_claim() -> String {
+    "roles".to_string()
+}
+
+pub struct JwtSettings {
+    pub jwks_file: Option<String>,
+    pub public_key_file: Option<String>,
+    pub issuer: Option<String>,
+    pub audience: Option<String>,
+    pub algorithms: Vec<String>,
+    pub roles_file: Option<String>,
+    pub leeway_seconds: u64,
+}
+
+/// Kind of a verification key, a token is only checked against keys of its algorithm's family.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum KeyFamily {
+    Rsa,
+    Ec,
+    Ed,
+}
+
+impl KeyFamily {
+    fn of_algorithm(i_algorithm: Algorithm) -> Option<KeyFamily> {
+        match i_algorithm {
+            Algorithm::RS256 | Algorithm::RS384 | Algorithm::RS512 | Algorithm::PS256 | Algorithm::PS384 | Algorithm::PS512 => Some(KeyFamily::Rsa),
+            Algorithm::ES256 | Algorithm::ES384 => Some(KeyFamily::Ec),
+            Algorithm::EdDSA => Some(KeyFamily::Ed),
+            Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => None
+        }
+    }
+
+    fn of_jwk(i_parameters: &AlgorithmParameters) -> Option<KeyFamily> {
+        match i_parameters {
+            AlgorithmParameters::RSA(_) => Some(KeyFamily::Rsa),
+            AlgorithmParameters::EllipticCurve(_) => Some(KeyFamily::Ec),
+            AlgorithmParameters::OctetKeyPair(_) => Some(KeyFamily::Ed),
+            AlgorithmParameters::OctetKey(_) => None
+        }
+    }
+}
+
+/// Validates bearer tokens offline against the configured JWKS or PEM keys.
+/// Signature, `iss`, `aud` and `exp` are always checked, then the roles claim is mapped to
+/// [`Permissions`] using the roles file.
+///
+/// The token's `alg` must be one of `jwt_algorithms` and each token is validated for its own
+/// algorithm only: `jsonwebtoken` refuses a [`Validation`] mixing families, such as the default
+/// `RS256,ES256`, with any key.
+pub struct JwtValidator {
+    keys: Vec<(Option<String>, KeyFamily, DecodingKey)>,
+    algorithms: Vec<Algorithm>,
+    leeway_seconds: u64,
+    issuer: String,
+    audience: String,
+    roles_claim: String,
+    roles: HashMap<String, Permissions>,
+}
+
+fn load_pem_key(i_pem: &[u8]) -> Result<(KeyFamily, DecodingKey), anyhow::Error> {
+    if let Ok(k) = DecodingKey::from_rsa_pem(i_pem) {
+        return Ok((KeyFamily::Rsa, k))
+    }
+    if let Ok(k) = DecodingKey::from_ec_pem(i_pem) {
+        return Ok((KeyFamily::Ec, k))
+    }
+    Ok((KeyFamily::Ed, DecodingKey::from_ed_pem(i_pem)?))
+}
+
+impl JwtValidator {
+    pub fn new(settings: JwtSettings) -> Result<JwtValidator, anyhow::Error> {
+
+        let mut keys = vec![];
+        if let Some(path) = &settings.jwks_file {
+            let jwks: JwkSet = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+            for jwk in jwks.keys.iter().filter(|k| k.is_supported()) {
+                if let Some(family) = KeyFamily::of_jwk(&jwk.algorithm) {
+                    keys.push((jwk.common.key_id.clone(), family, DecodingKey::from_jwk(jwk)?));
+                }
+            }
+        }
+        if let Some(path) = &settings.public_key_file {
+            let (family, key) = load_pem_key(&std::fs::read(path)?)?;
+            keys.push((None, family, key));
+        }
+        if keys.is_empty() {
+            return Err(anyhow::anyhow!("jwt authentication needs at least one key, set jwt_jwks_file or jwt_public_key_file"));
+        }
+
+        let issuer = match &settings.issuer {
+            Some(v) => v,
+            None => return Err(anyhow::anyhow!("jwt authentication needs jwt_issuer"))
+        };
+        let audience = match &settings.audience {
+            Some(v) => v,
+            None => return Err(anyhow::anyhow!("jwt authentication needs jwt_audience"))
+        };
+
+        let mut algorithms = vec![];
+        for alg in &settings.algorithms {
+            let algorithm = Algorithm::from_str(alg.trim())?;
+            if KeyFamily::of_algorithm(algorithm).is_none() {
+                return Err(anyhow::anyhow!("jwt algorithm {} is symmetric and not supported", alg));
+            }
+            algorithms.push(algorithm);
+        }
+        if algorithms.is_empty() {
+            return Err(anyhow::anyhow!("jwt authentication needs at least one algorithm in jwt_algorithms"));
+        }
+
+        let roles_file = match &settings.roles_file {
+            Some(path) => serde_json::from_str::<RolesFile>(&std::fs::read_to_string(path)?)?,
+            None => RolesFile { roles_claim: default_roles_claim(), roles: HashMap::new() }
+        };
+
+        Ok(JwtValidator {
+            keys,
+            algorithms,
+            leeway_seconds: settings.leeway_seconds,
+            issuer: issuer.clone(),
+            audience: audience.clone(),
+            roles_claim: roles_file.roles_claim,
+            roles: roles_file.roles,
+        })
+    }
+
+    pub fn keys_number(&self) -> usize {
+        self.keys.len()
+    }
+
+    /// Same checks for every token, only for the algorithm of its header.
+    fn validation(&self, i_algorithm: Algorithm) -> Validation {
+        let mut validation = Validation::new(i_algorithm);
+        validation.leeway = self.leeway_seconds;
+        validation.set_issuer(&[&self.issuer]);
+        validation.set_audience(&[&self.audience]);
+        validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+        validation
+    }
+
+    /// Key named by `kid`, or the only key of the family without `kid`, never a key of another family.
+    fn find_key(&self, i_kid: Option<&str>, i_family: KeyFamily) -> Option<&DecodingKey> {
+        let mut candidates = self.keys.iter().filter(|(_, family, _)| *family == i_family);
+        match i_kid {
+            Some(kid) => candidates.find(|(k, _, _)| k.as_deref() == Some(kid)).map(|(_, _, key)| key),
+            None => match (candidates.next(), candidates.next()) {
+                (Some((_, _, key)), None) => Some(key),
+                _ => None
+            }
+        }
+    }
+
+    fn roles_from_claims(&self, i_claims: &serde_json::Value) -> Vec<String> {
+        match &i_claims[&self.roles_claim] {
+            serde_json::Value::Array(v) => v.iter().filter_map(|r| r.as_str().map(|s| s.to_string())).collect(),
+            serde_json::Value::String(v) => v.split_whitespace().map(|s| s.to_string()).collect(),
+            _ => vec![]
+        }
+    }
+
+    pub fn authenticate(&self, i_token: &str) -> Result<Identity, anyhow::Error> {
+        let header = decode_header(i_token)?;
+        if !self.algorithms.contains(&header.alg) {
+            return Err(anyhow::anyhow!("token algorithm {:?} is not in jwt_algorithms", header.alg));
+        }
+        let family = match KeyFamily::of_algorithm(header.alg) {
+            Some(v) => v,
+            None => return Err(anyhow::anyhow!("token algorithm {:?} is not supported", header.alg))
+        };
+        let key = match self.find_key(header.kid.as_deref(), family) {
+            Some(v) => v,
+            None => return Err(anyhow::anyhow!("no {:?} key matches the token kid", family))
+        };
+        let token = decode::<serde_json::Value>(i_token, key, &self.validation(header.alg))?;
+
+        let granted: Vec<&Permissions> = self.roles_from_claims(&token.claims)
+            .iter()
+            .filter_map(|r| self.roles.get(r))
+            .collect();
+
+        Ok(Identity {
+            subject: token.claims["sub"].as_str().unwrap_or("").to_string(),
+            method: "jwt".to_string(),
+            permissions: Permissions::merge(&granted),
+            attributes: token.claims
+                .as_object()
+                .map(|claims| claims.iter().map(|(k, v)| (format!("claim:{}", k), v.clone())).collect())
+                .unwrap_or_default(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonwebtoken::{encode, get_current_timestamp, EncodingKey, Header};
+    use openssl::rsa::Rsa;
+    use serde_json::json;
+
+    fn rsa_keys(i_name: &str) -> (EncodingKey, String) {
+        let rsa = Rsa::generate(2048).unwrap();
+        let path = std::env::temp_dir().join(format!("jwt_test_{}_{}.pem", i_name, std::process::id()));
+        std::fs::write(&path, rsa.public_key_to_pem().unwrap()).unwrap();
+        let encoding_key = EncodingKey::from_rsa_pem(&rsa.private_key_to_pem().unwrap()).unwrap();
+        (encoding_key, path.to_string_lossy().to_string())
+    }
+
+    fn validator(i_public_key_file: &str) -> JwtValidator {
+        JwtValidator::new(JwtSettings {
+            jwks_file: None,
+            public_key_file: Some(i_public_key_file.to_string()),
+            issuer: Some("https://issuer.example".to_string()),
+            audience: Some("dbproxy".to_string()),
+            algorithms: crate::app_config::default_jwt_algorithms(),
+            roles_file: None,
+            leeway_seconds: 30,
+        }).unwrap()
+    }
+
+    fn claims() -> serde_json::Value {
+        json!({
+            "sub": "alice",
+            "iss": "https://issuer.example",
+            "aud": "dbproxy",
+            "exp": get_current_timestamp() + 600,
+            "tenant": "acme"
+        })
+    }
+
+    #[test]
+    fn rs256_token_is_valid_with_the_default_algorithms() {
+        let (encoding_key, public_key_file) = rsa_keys("rs256");
+        let token = encode(&Header::new(Algorithm::RS256), &claims(), &encoding_key).unwrap();
+        let identity = validator(&public_key_file).authenticate(&token).unwrap();
+        assert_eq!(identity.subject, "alice");
+        assert_eq!(identity.method, "jwt");
+        assert_eq!(identity.attributes["claim:tenant"], json!("acme"));
+    }
+
+    #[test]
+    fn algorithm_outside_the_allow_list_is_refused() {
+        let (encoding_key, public_key_file) = rsa_keys("rs512");
+        let token = encode(&Header::new(Algorithm::RS512), &claims(), &encoding_key).unwrap();
+        assert!(validator(&public_key_file).authenticate(&token).is_err());
+    }
+
+    #[test]
+    fn symmetric_token_is_refused() {
+        let (_, public_key_file) = rsa_keys("hs256");
+        let token = encode(&Header::new(Algorithm::HS256), &claims(), &EncodingKey::from_secret(b"secret")).unwrap();
+        assert!(validator(&public_key_file).authenticate(&token).is_err());
+    }
+
+    #[test]
+    fn wrong_audience_and_expired_tokens_are_refused() {
+        let (encoding_key, public_key_file) = rsa_keys("claims");
+        let jwt = validator(&public_key_file);
+        let mut wrong_audience = claims();
+        wrong_audience["aud"] = json!("another");
+        let token = encode(&Header::new(Algorithm::RS256), &wrong_audience, &encoding_key).unwrap();
+        assert!(jwt.authenticate(&token).is_err());
+        let mut expired = claims();
+        expired["exp"] = json!(get_current_timestamp() - 3600);
+        let token = encode(&Header::new(Algorithm::RS256), &expired, &encoding_key).unwrap();
+        assert!(jwt.authenticate(&token).is_err());
+    }
+
+    #[test]
+    fn key_is_picked_by_kid_within_the_family() {
+        let (encoding_key, public_key_file) = rsa_keys("kid");
+        let jwt = validator(&public_key_file);
+        let mut header = Header::new(Algorithm::RS256);
+        header.kid = Some("unknown".to_string());
+        let token = encode(&header, &claims(), &encoding_key).unwrap();
+        assert!(jwt.authenticate(&token).is_err());
+        assert!(jwt.find_key(None, KeyFamily::Rsa).is_some());
+        assert!(jwt.find_key(None, KeyFamily::Ec).is_none());
+    }
+}
@@ -0,0 +1,204 @@
+pub mod api_key;
+pub mod jwt;
+pub mod mtls;
+
+use actix_web::{
+    body::{BoxBody, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    http::header::{ContentType, AUTHORIZATION},
+    middleware::Next,
+    web,
+    Error,
+    HttpMessage,
+    HttpRequest,
+    HttpResponse
+};
+use serde::Deserialize;
+use std::collections::HashMap;
+use serde_json::json;
+use tracing::{warn};
+use crate::common::{erfn, AppState};
+use crate::request_id::RequestId;
+use self::api_key::{ApiKeyStore, API_KEY_HEADER};
+use self::jwt::JwtValidator;
+use self::mtls::{CertificateIdentities, ClientCertificate};
+
+pub const ALL_STATEMENTS: &str = "*";
+
+/// Prefixes of the identity attributes a catalog `identity_binding` can refer to.
+pub const IDENTITY_ATTRIBUTE_SOURCES: [&str; 3] = ["claim:", "api_key:", "certificate:"];
+
+/// What an API key or a JWT role grants.
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct Permissions {
+    /// Catalog statement ids, `"*"` grants all of them.
+    #[serde(default)]
+    pub statements: Vec<String>,
+    /// Catalog tags, every statement carrying one of them is granted.
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub allow_direct_statement: bool,
+    #[serde(default)]
+    pub read_only: bool,
+    /// Access to the `/v2/admin` endpoints.
+    #[serde(default)]
+    pub admin: bool,
+    /// Allows the `trace` flag of the operations.
+    #[serde(default)]
+    pub allow_trace: bool,
+}
+
+impl Permissions {
+    /// Union of several grants, the result is read only only if all of them are.
+    pub fn merge(i_grants: &[&Permissions]) -> Permissions {
+        let mut merged = Permissions {
+            read_only: true,
+            ..Permissions::default()
+        };
+        for grant in i_grants {
+            merged.statements.extend(grant.statements.iter().cloned());
+            merged.tags.extend(grant.tags.iter().cloned());
+            merged.allow_direct_statement |= grant.allow_direct_statement;
+            merged.read_only &= grant.read_only;
+            merged.admin |= grant.admin;
+            merged.allow_trace |= grant.allow_trace;
+        }
+        merged
+    }
+}
+
+/// Who is calling, and what they are allowed to run.
+/// Attached to the request extensions by [`authenticate`].
+#[derive(Debug, Clone)]
+pub struct Identity {
+    pub subject: String,
+    pub method: String,
+    pub permissions: Permissions,
+    /// Verified attributes, keyed by source: `claim:<name>` for JWT claims, `api_key:<name>` for
+    /// API key metadata, `certificate:<field>` for the client certificate.
+    pub attributes: HashMap<String, serde_json::Value>,
+}
+
+impl Identity {
+    /// Used when no authentication method is configured, keeps the statement endpoints open as
+    /// before. The admin endpoints stay closed unless `i_admin` is set with `ADMIN_ALLOW_ANONYMOUS`,
+    /// and tracing unless `i_trace` is set with `ANONYMOUS_ALLOW_TRACE`.
+    pub fn anonymous(i_admin: bool, i_trace: bool) -> Identity {
+        Identity {
+            subject: "anonymous".to_string(),
+            method: "none".to_string(),
+            permissions: Permissions {
+                statements: vec![ALL_STATEMENTS.to_string()],
+                tags: vec![],
+                allow_direct_statement: true,
+                read_only: false,
+                admin: i_admin,
+                allow_trace: i_trace,
+            },
+            attributes: HashMap::new(),
+        }
+    }
+
+    pub fn can_execute(&self, i_statement_id: &str, i_statement_tags: &[String]) -> bool {
+        self.permissions.statements.iter().any(|s| s == ALL_STATEMENTS || s == i_statement_id)
+            || self.permissions.tags.iter().any(|t| i_statement_tags.contains(t))
+    }
+
+    pub fn from_request(request: &HttpRequest) -> Identity {
+        request.extensions().get::<Identity>().cloned().unwrap_or_else(|| Identity {
+            subject: "unauthenticated".to_string(),
+            method: "none".to_string(),
+            permissions: Permissions {
+                read_only: true,
+                ..Permissions::default()
+            },
+            attributes: HashMap::new(),
+        })
+    }
+}
+
+pub struct Authenticator {
+    pub api_keys: Option<ApiKeyStore>,
+    pub jwt: Option<JwtValidator>,
+    pub certificates: Option<CertificateIdentities>,
+    pub admin_allow_anonymous: bool,
+    pub anonymous_allow_trace: bool,
+}
+
+impl Authenticator {
+    pub fn is_enabled(&self) -> bool {
+        self.api_keys.is_some() || self.jwt.is_some() || self.certificates.is_some()
+    }
+
+    /// API key first, then bearer token, then the client certificate.
+    fn authenticate(&self, request: &ServiceRequest, certificate: Option<&ClientCertificate>) -> Option<Identity> {
+        if let Some(api_key) = request.headers().get(API_KEY_HEADER).and_then(|v| v.to_str().ok()) {
+            return self.api_keys.as_ref()?.authenticate(api_key)
+        }
+        let bearer = match request
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer ")) {
+            Some(v) => v,
+            None => return self.certificates.as_ref()?.authenticate(certificate?)
+        };
+        match self.jwt.as_ref()?.authenticate(bearer.trim()) {
+            Ok(v) => Some(v),
+            Err(e) => {
+                warn!("Rejected bearer token: {}", e);
+                None
+            }
+        }
+    }
+}
+
+fn unauthorized(request: ServiceRequest, i_message: &str) -> ServiceResponse<BoxBody> {
+    let mut e = erfn(401, i_message.to_string(), "unauthorized".to_string(), i_message.to_string());
+    e["request_id"] = json!(RequestId::from_request(request.request()));
+    request.into_response(
+        HttpResponse::Unauthorized()
+            .insert_header(ContentType::json())
+            .body(e.to_string())
+    )
+}
+
+/// Resolves the caller's [`Identity`] before the statement handlers run.
+/// Requests without valid credentials never reach the handlers.
+pub async fn authenticate(
+    request: ServiceRequest,
+    next: Next<impl MessageBody + 'static>
+) -> Result<ServiceResponse<BoxBody>, Error> {
+
+    let identity = {
+        let state = match request.app_data::<web::Data<AppState>>() {
+            Some(v) => v,
+            None => return Ok(unauthorized(request, "authentication_not_configured"))
+        };
+        let authenticator = &state.authenticator;
+        let certificate = request.conn_data::<ClientCertificate>();
+
+        let identity = if !authenticator.is_enabled() {
+            Some(Identity::anonymous(authenticator.admin_allow_anonymous, authenticator.anonymous_allow_trace))
+        } else {
+            authenticator.authenticate(&request, certificate)
+        };
+
+        identity.map(|mut v| {
+            if let Some(c) = certificate {
+                v.attributes.extend(c.attributes());
+            }
+            v
+        })
+    };
+
+    let identity = match identity {
+        Some(v) => v,
+        None => return Ok(unauthorized(request, "invalid_or_missing_credentials"))
+    };
+
+    request.extensions_mut().insert(identity);
+
+    Ok(next.call(request).await?.map_into_boxed_body())
+}
@@ -0,0 +1,106 @@
+use actix_tls::accept::openssl::TlsStream;
+use actix_web::{dev::Extensions, rt::net::TcpStream};
+use openssl::x509::X509Ref;
+use serde::Deserialize;
+use std::any::Any;
+use std::collections::HashMap;
+use anyhow::Result;
+use crate::auth::{Identity, Permissions};
+
+/// The verified client certificate of a TLS connection, stored in the connection data.
+#[derive(Debug, Clone)]
+pub struct ClientCertificate {
+    pub subject: String,
+    pub fields: HashMap<String, String>,
+    pub san: Vec<String>,
+}
+
+impl ClientCertificate {
+    pub fn from_x509(cert: &X509Ref) -> ClientCertificate {
+        let mut fields = HashMap::new();
+        let mut subject = vec![];
+        for entry in cert.subject_name().entries() {
+            let name = entry.object().nid().short_name().unwrap_or("UNKNOWN").to_string();
+            let value = entry.data().to_string().unwrap_or_default();
+            subject.push(format!("{}={}", name, value));
+            fields.insert(name, value);
+        }
+
+        let san = cert
+            .subject_alt_names()
+            .map(|names| names.iter().filter_map(|n| {
+                n.dnsname()
+                    .or_else(|| n.email())
+                    .or_else(|| n.uri())
+                    .map(|v| v.to_string())
+            }).collect())
+            .unwrap_or_default();
+
+        ClientCertificate {
+            subject: subject.join(","),
+            fields,
+            san,
+        }
+    }
+
+    pub fn common_name(&self) -> Option<&String> {
+        self.fields.get("CN")
+    }
+
+    /// `certificate:subject`, `certificate:san` and one `certificate:<field>` per subject field.
+    pub fn attributes(&self) -> HashMap<String, serde_json::Value> {
+        let mut attributes: HashMap<String, serde_json::Value> = self.fields
+            .iter()
+            .map(|(k, v)| (format!("certificate:{}", k), serde_json::Value::String(v.clone())))
+            .collect();
+        attributes.insert("certificate:subject".to_string(), serde_json::Value::String(self.subject.clone()));
+        attributes.insert("certificate:san".to_string(), serde_json::json!(self.san));
+        attributes
+    }
+}
+
+/// `HttpServer::on_connect` callback, exposes the client certificate to the handlers.
+pub fn on_connect(connection: &dyn Any, extensions: &mut Extensions) {
+    if let Some(tls) = connection.downcast_ref::<TlsStream<TcpStream>>() {
+        if let Some(cert) = tls.ssl().peer_certificate() {
+            extensions.insert(ClientCertificate::from_x509(&cert));
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct CertificateIdentitiesFile {
+    identities: HashMap<String, Permissions>,
+}
+
+/// Permissions granted to client certificates, by subject common name.
+pub struct CertificateIdentities {
+    identities: HashMap<String, Permissions>,
+}
+
+impl CertificateIdentities {
+    pub fn from_file(i_path: &str) -> Result<CertificateIdentities, anyhow::Error> {
+        let content = std::fs::read_to_string(i_path)?;
+        let identities_file: CertificateIdentitiesFile = serde_json::from_str(&content)?;
+        Ok(CertificateIdentities { identities: identities_file.identities })
+    }
+
+    pub fn len(&self) -> usize {
+        self.identities.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.identities.is_empty()
+    }
+
+    pub fn authenticate(&self, certificate: &ClientCertificate) -> Option<Identity> {
+        let common_name = certificate.common_name()?;
+        let permissions = self.identities.get(common_name)?;
+        Some(Identity {
+            subject: certificate.subject.clone(),
+            method: "certificate".to_string(),
+            permissions: permissions.clone(),
+            attributes: HashMap::new(),
+        })
+    }
+}
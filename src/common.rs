@@ -4,19 +4,36 @@ use tokio::sync::Semaphore;
 use std::sync::Arc;
 use actix_web::{
     web, 
-    HttpRequest
+    HttpRequest,
+    HttpResponse,
+    http::{header::ContentType, StatusCode}
 };
 use serde_json::json;
 use scylla::{query::Query, Session, SessionBuilder, QueryResult};
-use scylla::transport::load_balancing::{DcAwareRoundRobinPolicy, TokenAwarePolicy};
-use scylla::transport::Compression;
+use scylla::transport::load_balancing::{ChildLoadBalancingPolicy, DcAwareRoundRobinPolicy, LoadBalancingPolicy, RoundRobinPolicy, TokenAwarePolicy};
 use crate::queries::{get_statements};
-use scylla::frame::value::ValueList;
-use std::collections::HashMap;
+use scylla::frame::value::{SerializedValues, ValueList};
+use scylla::transport::partitioner::{Murmur3Partitioner, Partitioner};
+use std::collections::{BTreeMap, HashMap};
 use anyhow::Result;
 use base64::{Engine as _, engine::general_purpose};
 use scylla::Bytes;
-use crate::app_config::load_ssl_scylla_config;
+use crate::app_config::{DriverSettings, DEFAULT_CLUSTER, LOAD_BALANCING_ROUND_ROBIN};
+use crate::cql_classifier::GuardPolicy;
+use crate::maintenance::ReadOnlySwitch;
+use crate::rate_limit::RateLimiter;
+use crate::audit::Auditor;
+use crate::redaction::Redactor;
+use crate::metrics::Metrics;
+use crate::slow_query::SlowQueryLog;
+use crate::health::HealthChecker;
+use crate::tls_certificate::ServerCertificate;
+use crate::shutdown::Shutdown;
+use crate::db_credentials::DbCredentials;
+use tracing::{debug, error, field, info, instrument, warn, Span};
+use std::borrow::Cow;
+use uuid::Uuid;
+use crate::auth::{Authenticator, Identity, IDENTITY_ATTRIBUTE_SOURCES};
 
 pub const APPLICATION_JSON: &str = "application/json";
 
@@ -52,8 +69,13 @@ pub fn init_input(request: HttpRequest, body: web::Bytes) -> Result<serde_json::
     }
 
     let querystring = request.query_string();
+    let identity = Identity::from_request(&request);
 
     let oj = json!({
+        "identity": {
+            "subject": identity.subject,
+            "method": identity.method
+        },
         "body": injson,
         "method": request.method().to_string(),
         "path": request.path().to_string(),
@@ -62,11 +84,28 @@ pub fn init_input(request: HttpRequest, body: web::Bytes) -> Result<serde_json::
         "uri": request.uri().to_string()
     });
 
-    println!("{:?}",oj);
+    if let Some(state) = request.app_data::<web::Data<AppState>>() {
+        debug!(request = %state.redactor.redact_request(&oj, &state.db_svc.p_queries_attributes), "Request received");
+    }
 
     Ok(oj)
 }
 
+pub fn sha256_hex(i_value: &str) -> String {
+    openssl::sha::sha256(i_value.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+pub fn read_only_error() -> serde_json::Value {
+    erfn(503, "the proxy is in read only mode, writes are disabled".to_string(), "maintenance_read_only".to_string(), "maintenance_read_only".to_string())
+}
+
+pub fn draining_error() -> serde_json::Value {
+    erfn(503, "the proxy is shutting down, retry on another instance".to_string(), "draining".to_string(), "draining".to_string())
+}
+
 pub fn decode_status_code(status_code: &serde_json::Value) -> Result<u16, u16> {
 
     let u64_status_code: u64 = status_code.as_u64().unwrap_or(500);
@@ -79,12 +118,48 @@ pub fn decode_status_code(status_code: &serde_json::Value) -> Result<u16, u16> {
 	Ok(ret_status_code)
 }
 
+/// Builds the HTTP response for an `erfn` error, with the `Retry-After` and `RateLimit-*`
+/// headers when the error comes from the rate limiter.
+pub fn error_response(e: &serde_json::Value) -> HttpResponse {
+    let ret_status_code: u16 = decode_status_code(&e["status_code"]).unwrap_or(500);
+    let mut response = HttpResponse::build(StatusCode::from_u16(ret_status_code).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR));
+    response.insert_header(ContentType::json());
+    if let Some(rate_limit) = e.get("rate_limit") {
+        let reset = rate_limit["reset"].as_u64().unwrap_or(1).to_string();
+        response.insert_header(("Retry-After", reset.clone()));
+        response.insert_header(("RateLimit-Limit", rate_limit["limit"].as_u64().unwrap_or(0).to_string()));
+        response.insert_header(("RateLimit-Remaining", "0"));
+        response.insert_header(("RateLimit-Reset", reset));
+    }
+    response.body(e.to_string())
+}
+
+/// What the driver tells about an execution, next to the rows.
+#[derive(Debug, Clone, Default)]
+pub struct ExecutionInfo {
+    pub page_size: Option<u64>,
+    /// Bound values of a prepared statement, kept to estimate its coordinator when the
+    /// operation turns out to be slow. Unprepared statements do not have it.
+    pub routing_values: Option<SerializedValues>,
+    pub tracing_id: Option<Uuid>,
+}
+
 pub struct ScyllaService {
+    /// Cluster name, `default` for the cluster of the top level `db_*` settings.
+    pub name: String,
     pub parallelism: usize,
     pub db_session: Arc<Session>,
     pub p_queries: Arc<HashMap<String, PreparedStatement>>,
+    /// Statements of the cluster that Scylla refused to prepare, with its error. They are left
+    /// out of `p_queries`, fail the `catalog` readiness check and answer with a 503.
+    pub prepare_errors: Arc<HashMap<String, String>>,
+    /// Attributes of the whole catalog, including the statements of the other clusters.
     pub p_queries_attributes: Arc<HashMap<String, HashMap<String, serde_json::Value>>>,
-    pub p_runtime_queries: Arc<HashMap<String, String>>
+    pub p_runtime_queries: Arc<HashMap<String, String>>,
+    pub datacenter: String,
+    /// The configured seeds, as given.
+    pub contact_points: Vec<String>,
+    pub credentials: Arc<DbCredentials>
 }
 
 fn get_paging_state_from_result(
@@ -115,43 +190,50 @@ fn get_json_rows_from_result(
 }
 
 impl ScyllaService {
-    pub async fn new(
-        scylla_node_0: String, 
-        scylla_node_1: String, 
-        scylla_node_2: String, 
-        scylla_user: String,
-        scylla_password: String,
-        scylla_parallelism: usize, 
-        scylla_datacenter: String
-    ) -> Self {
-
-        println!("*** ScyllaService: \n\tConnecting to: \n\t\t{} \n\t\t{} \n\t\t{} \n\tDataCenter: \n\t\t{} \n\tParallelism: \n\t\t{}", 
-            scylla_node_0, 
-            scylla_node_1, 
-            scylla_node_2, 
-            scylla_datacenter,
-            scylla_parallelism
+    pub async fn new(i_name: &str, settings: DriverSettings) -> Self {
+
+        info!("ScyllaService {}: Connecting to: {}. DataCenter: {}. Parallelism: {}. Load balancing: {} (token aware: {}). TLS: {}", 
+            i_name,
+            settings.contact_points.join(", "), 
+            settings.datacenter,
+            settings.parallelism,
+            settings.load_balancing,
+            settings.token_aware,
+            settings.ssl_context.is_some()
         );
 
-        let dc_robin = Box::new(DcAwareRoundRobinPolicy::new(scylla_datacenter.to_string()));
-        let policy = Arc::new(TokenAwarePolicy::new(dc_robin));
-
-        let ssl_conf = load_ssl_scylla_config().expect("^^^ SSL CONFIG ERROR");
+        let child_policy: Box<dyn ChildLoadBalancingPolicy> = if settings.load_balancing == LOAD_BALANCING_ROUND_ROBIN {
+            Box::new(RoundRobinPolicy::new())
+        } else {
+            let mut dc_robin = DcAwareRoundRobinPolicy::new(settings.datacenter.clone());
+            dc_robin.set_include_remote_nodes(settings.include_remote_nodes);
+            Box::new(dc_robin)
+        };
+        let policy: Arc<dyn LoadBalancingPolicy> = if settings.token_aware {
+            Arc::new(TokenAwarePolicy::new(child_policy))
+        } else {
+            Arc::from(child_policy as Box<dyn LoadBalancingPolicy>)
+        };
 
-        let session: Session = SessionBuilder::new()
-            .known_node(scylla_node_0)
-            .known_node(scylla_node_1)
-            .known_node(scylla_node_2)
-            .disallow_shard_aware_port(true)
-            .user(scylla_user, scylla_password)
-            .ssl_context(Some(ssl_conf.build()))
+        let mut builder = SessionBuilder::new()
+            .known_nodes(&settings.contact_points)
+            .disallow_shard_aware_port(!settings.shard_aware_port)
+            .pool_size(settings.pool_size.clone())
+            .connection_timeout(settings.connection_timeout)
+            .authenticator_provider(settings.credentials.clone())
+            .ssl_context(settings.ssl_context.clone())
             .load_balancing(policy)
-            .compression(Some(Compression::Lz4))
+            .compression(settings.compression);
+        if let Some(interval) = settings.keepalive_interval {
+            builder = builder.keepalive_interval(interval);
+        }
+        let session: Session = builder
             .build()
             .await
             .expect("^^^ Scylla Session Error");
 
         let mut map_p_queries = HashMap::new();
+        let mut map_prepare_errors = HashMap::new();
         let mut map_p_queries_attributes = HashMap::new();
         let mut map_p_runtime_queries = HashMap::new();
 
@@ -162,20 +244,29 @@ impl ScyllaService {
             let is_paged_query = vv["is_paged"].as_bool().unwrap();
             let is_prepared_query = vv["is_prepared"].as_bool().unwrap();
             let per_page_results = vv["per_page_results"].as_f64().unwrap();
-            if is_prepared_query {
-                let prepared_s: PreparedStatement = if is_paged_query {
+            let cluster = vv.get("cluster").and_then(|c| c.as_str()).unwrap_or(DEFAULT_CLUSTER);
+            if cluster != i_name {
+                // prepared on the session of its own cluster
+            } else if is_prepared_query {
+                let prepared_s = if is_paged_query {
                     let per_page_results = vv["per_page_results"].as_u64().unwrap();
                     session
                         .prepare(Query::new(vv["statement"].as_str().unwrap().to_string()).with_page_size(per_page_results.try_into().unwrap()))
                         .await
-                        .expect(format!("Error Creating {} Prepared Query", kkey).as_str())
                 } else {
                     session
                         .prepare(vv["statement"].as_str().unwrap().to_string())
                         .await
-                        .expect(format!("Error Creating {} Prepared Query", kkey).as_str())
                 };
-                map_p_queries.insert(kkey.clone(), prepared_s);
+                match prepared_s {
+                    Ok(v) => {
+                        map_p_queries.insert(kkey.clone(), v);
+                    },
+                    Err(e) => {
+                        error!("ScyllaService {}: Error Creating {} Prepared Query: {}", i_name, kkey, e);
+                        map_prepare_errors.insert(kkey.clone(), e.to_string());
+                    }
+                };
             } else {
                 map_p_runtime_queries.insert(kkey.clone(), vv["statement"].as_str().unwrap().to_string());
             }
@@ -185,17 +276,36 @@ impl ScyllaService {
             map_two.insert("is_prepared".to_string(), serde_json::Value::Bool(is_prepared_query));
             map_two.insert("per_page_results".to_string(), serde_json::Value::Number(serde_json::Number::from_f64(per_page_results).unwrap()));
             map_two.insert("casting".to_string(), serde_json::Value::Object(vv["casting"].as_object().unwrap().clone()));
+            map_two.insert("tags".to_string(), vv.get("tags").cloned().unwrap_or(json!([])));
+            let identity_binding = vv.get("identity_binding").cloned().unwrap_or(json!({}));
+            for (position, source) in identity_binding.as_object().expect("identity_binding must be an object") {
+                let source = source.as_str().unwrap_or("");
+                if !IDENTITY_ATTRIBUTE_SOURCES.iter().any(|p| source.starts_with(p)) || !vv["casting"].as_object().unwrap().contains_key(position) {
+                    panic!("Statement {} has an invalid identity_binding {}: {}", kkey, position, source);
+                }
+            }
+            map_two.insert("identity_binding".to_string(), identity_binding);
+            map_two.insert("sensitive".to_string(), vv.get("sensitive").cloned().unwrap_or(json!([])));
+            map_two.insert("sensitive_fields".to_string(), vv.get("sensitive_fields").cloned().unwrap_or(json!([])));
+            map_two.insert("rate_limit".to_string(), vv.get("rate_limit").cloned().unwrap_or(serde_json::Value::Null));
+            map_two.insert("slow_query_threshold_ms".to_string(), vv.get("slow_query_threshold_ms").cloned().unwrap_or(serde_json::Value::Null));
+            map_two.insert("cluster".to_string(), json!(cluster));
             map_p_queries_attributes.insert(kkey.clone(), map_two);
         }
 
         let db_session = Arc::new(session);
 
         ScyllaService {
-            db_session: db_session,
-            parallelism: scylla_parallelism,
+            name: i_name.to_string(),
+            db_session,
+            parallelism: settings.parallelism,
             p_queries: Arc::new(map_p_queries),
+            prepare_errors: Arc::new(map_prepare_errors),
             p_queries_attributes: Arc::new(map_p_queries_attributes),
-            p_runtime_queries: Arc::new(map_p_runtime_queries)
+            p_runtime_queries: Arc::new(map_p_runtime_queries),
+            datacenter: settings.datacenter,
+            contact_points: settings.contact_points,
+            credentials: settings.credentials
         }
     }
 
@@ -211,25 +321,39 @@ impl ScyllaService {
         Arc::new(ret_statement)
     }
 
+    #[instrument(name = "cql_execute", skip_all, fields(otel.kind = "client", db.system = "cassandra", db.statement_id = "direct_statement", otel.status_code = field::Empty))]
     pub async fn direct_statement(
         &self, 
         i_statement: String,
         per_page_results: u64,
-        i_paging_state: String
-    ) -> Result<serde_json::Value, anyhow::Error> {
+        i_paging_state: String,
+        trace: bool
+    ) -> Result<(serde_json::Value, ExecutionInfo), anyhow::Error> {
+        let result = self.direct_query(i_statement, per_page_results, i_paging_state, trace).await;
+        record_cql_result(&result);
+        result
+    }
+
+    async fn direct_query(
+        &self, 
+        i_statement: String,
+        per_page_results: u64,
+        i_paging_state: String,
+        trace: bool
+    ) -> Result<(serde_json::Value, ExecutionInfo), anyhow::Error> {
 
         let session = self.db_session.clone();
 
-        let result_arc = if i_paging_state == "".to_string() {
+        let result_arc = if i_paging_state.is_empty() {
             if per_page_results > 0 {
                 Arc::new(session.query(
-                    Query::new(&i_statement).with_page_size(per_page_results.try_into().unwrap()),
+                    traced(Query::new(&i_statement).with_page_size(per_page_results.try_into().unwrap()), trace),
                     &[],
                 )
                 .await?)
             } else {
                 Arc::new(session.query(
-                    Query::new(&i_statement),
+                    traced(Query::new(&i_statement), trace),
                     &[],
                 )
                 .await?)
@@ -238,14 +362,14 @@ impl ScyllaService {
             let paging_state: scylla::Bytes = Bytes::from(general_purpose::STANDARD_NO_PAD.decode(i_paging_state).unwrap_or(b"".to_vec()));
             if per_page_results > 0 {
                 Arc::new(session.query_paged(
-                    Query::new(&i_statement).with_page_size(per_page_results.try_into().unwrap()),
+                    traced(Query::new(&i_statement).with_page_size(per_page_results.try_into().unwrap()), trace),
                     &[],
                     Some(paging_state)
                 )
                 .await?)
             } else {
                 Arc::new(session.query_paged(
-                    Query::new(&i_statement),
+                    traced(Query::new(&i_statement), trace),
                     &[],
                     Some(paging_state)
                 )
@@ -256,34 +380,42 @@ impl ScyllaService {
         let o_page = get_paging_state_from_result(&result_arc);
         let resout = get_json_rows_from_result(&result_arc)?;
 
-        Ok(
+        Ok((
             json!(
                 {
                     "records": resout,
                     "records_number": resout.len(),
                     "paging_state": o_page
                 }
-            )
-        )
+            ),
+            ExecutionInfo {
+                page_size: if per_page_results > 0 { Some(per_page_results) } else { None },
+                routing_values: None,
+                tracing_id: result_arc.tracing_id
+            }
+        ))
     }
 
+    #[instrument(name = "cql_execute", skip_all, fields(otel.kind = "client", db.system = "cassandra", db.statement_id = %i_prepared_statement, otel.status_code = field::Empty))]
     pub async fn cql_statement(
         &self, 
         i_prepared_statement: String, 
         i_substitutions: impl ValueList,
         i_paging_state: String,
-    ) -> Result<serde_json::Value, anyhow::Error> {
+        trace: bool
+    ) -> Result<(serde_json::Value, ExecutionInfo), anyhow::Error> {
 
         let queries_metadata = self.p_queries_attributes.clone();
 
-        let result: serde_json::Value = if queries_metadata[&i_prepared_statement]["is_query"].as_bool().unwrap_or(false) {
+        let result = if queries_metadata[&i_prepared_statement]["is_query"].as_bool().unwrap_or(false) {
             self.cql_query(
-                i_prepared_statement.clone(), &i_substitutions, i_paging_state, queries_metadata[&i_prepared_statement]["is_prepared"].as_bool().unwrap_or(true), queries_metadata[&i_prepared_statement]["per_page_results"].as_u64().unwrap_or(0)).await?
+                i_prepared_statement.clone(), &i_substitutions, i_paging_state, queries_metadata[&i_prepared_statement]["is_prepared"].as_bool().unwrap_or(true), queries_metadata[&i_prepared_statement]["per_page_results"].as_u64().unwrap_or(0), trace).await
         } else {
-            self.cql_delupsert(i_prepared_statement.clone(), &i_substitutions, queries_metadata[&i_prepared_statement]["is_prepared"].as_bool().unwrap_or(true)).await?
+            self.cql_delupsert(i_prepared_statement.clone(), &i_substitutions, queries_metadata[&i_prepared_statement]["is_prepared"].as_bool().unwrap_or(true), trace).await
         };
 
-        Ok(result)
+        record_cql_result(&result);
+        result
     }
 
     pub async fn cql_query(
@@ -292,30 +424,32 @@ impl ScyllaService {
         i_substitutions: impl ValueList,
         i_paging_state: String,
         is_prepared: bool,
-        per_page_results: u64
-    ) -> Result<serde_json::Value, anyhow::Error> {
+        per_page_results: u64,
+        trace: bool
+    ) -> Result<(serde_json::Value, ExecutionInfo), anyhow::Error> {
 
         let session = self.db_session.clone();
-        let pepared_to_execute = self.p_queries.clone();
         let runtime_to_execute = self.p_runtime_queries.clone();
 
-        let result_arc = if i_paging_state == "".to_string() {
+        let values = i_substitutions.serialized()?.into_owned();
+
+        let result_arc = if i_paging_state.is_empty() {
             if is_prepared {
                 Arc::new(session.execute(
-                    &pepared_to_execute[&i_prepared_statement],
-                    i_substitutions,
+                    &*self.prepared_for(&i_prepared_statement, trace)?,
+                    &values,
                 )
                 .await?)
             } else if per_page_results > 0 {
                 Arc::new(session.query(
-                    Query::new(&runtime_to_execute[&i_prepared_statement]).with_page_size(per_page_results.try_into().unwrap()),
-                    i_substitutions,
+                    traced(Query::new(&runtime_to_execute[&i_prepared_statement]).with_page_size(per_page_results.try_into().unwrap()), trace),
+                    &values,
                 )
                 .await?)
             } else {
                 Arc::new(session.query(
-                    Query::new(&runtime_to_execute[&i_prepared_statement]),
-                    i_substitutions,
+                    traced(Query::new(&runtime_to_execute[&i_prepared_statement]), trace),
+                    &values,
                 )
                 .await?)
             }
@@ -323,22 +457,22 @@ impl ScyllaService {
             let paging_state: scylla::Bytes = Bytes::from(general_purpose::STANDARD_NO_PAD.decode(i_paging_state).unwrap_or(b"".to_vec()));
             if is_prepared {
                 Arc::new(session.execute_paged(
-                    &pepared_to_execute[&i_prepared_statement],
-                    i_substitutions,
+                    &*self.prepared_for(&i_prepared_statement, trace)?,
+                    &values,
                     Some(paging_state)
                 )
                 .await?)
             } else if per_page_results > 0 {
                 Arc::new(session.query_paged(
-                    Query::new(&runtime_to_execute[&i_prepared_statement]).with_page_size(per_page_results.try_into().unwrap()),
-                    i_substitutions,
+                    traced(Query::new(&runtime_to_execute[&i_prepared_statement]).with_page_size(per_page_results.try_into().unwrap()), trace),
+                    &values,
                     Some(paging_state)
                 )
                 .await?)
             } else {
                 Arc::new(session.query_paged(
-                    Query::new(&runtime_to_execute[&i_prepared_statement]),
-                    i_substitutions,
+                    traced(Query::new(&runtime_to_execute[&i_prepared_statement]), trace),
+                    &values,
                     Some(paging_state)
                 )
                 .await?)
@@ -348,15 +482,20 @@ impl ScyllaService {
         let o_page = get_paging_state_from_result(&result_arc);
         let resout = get_json_rows_from_result(&result_arc)?;
 
-        Ok(
+        Ok((
             json!(
                 {
                     "records": resout,
                     "records_number": resout.len(),
                     "paging_state": o_page
                 }
-            )
-        )
+            ),
+            ExecutionInfo {
+                page_size: if per_page_results > 0 { Some(per_page_results) } else { None },
+                routing_values: if is_prepared { Some(values) } else { None },
+                tracing_id: result_arc.tracing_id
+            }
+        ))
     }
 
     pub async fn cql_delupsert(
@@ -364,40 +503,174 @@ impl ScyllaService {
         i_prepared_statement: String, 
         i_substitutions: impl ValueList,
         is_prepared: bool,
-    ) -> Result<serde_json::Value, anyhow::Error> {
+        trace: bool
+    ) -> Result<(serde_json::Value, ExecutionInfo), anyhow::Error> {
 
         let session = self.db_session.clone();
-        let pepared_to_execute = self.p_queries.clone();
         let runtime_to_execute = self.p_runtime_queries.clone();
 
-        if is_prepared {
+        let values = i_substitutions.serialized()?.into_owned();
+
+        let query_result = if is_prepared {
             session.execute(
-                &pepared_to_execute[&i_prepared_statement],
-                i_substitutions,
+                &*self.prepared_for(&i_prepared_statement, trace)?,
+                &values,
             )
-            .await?;
+            .await?
         } else {
             session.query(
-                Query::new(&runtime_to_execute[&i_prepared_statement]),
-                i_substitutions,
+                traced(Query::new(&runtime_to_execute[&i_prepared_statement]), trace),
+                &values,
             )
-            .await?;
-        }
+            .await?
+        };
 
-        Ok(
+        Ok((
             json!(
                 {
                     "records": [],
                     "records_number": 0,
                     "paging_state": ""
                 }
-            )
-        )
+            ),
+            ExecutionInfo {
+                page_size: None,
+                routing_values: if is_prepared { Some(values) } else { None },
+                tracing_id: query_result.tracing_id
+            }
+        ))
+    }
+
+    /// Cheapest round trip to the cluster, used by the readiness probe.
+    pub async fn ping(&self) -> Result<(), anyhow::Error> {
+        self.db_session.query("SELECT now() FROM system.local", &[]).await?;
+        Ok(())
+    }
+
+    /// Prepared statements are shared, tracing is set on a copy.
+    fn prepared_for(&self, i_prepared_statement: &str, trace: bool) -> Result<Cow<'_, PreparedStatement>, anyhow::Error> {
+        let prepared = match self.p_queries.get(i_prepared_statement) {
+            Some(v) => v,
+            None => return Err(anyhow::anyhow!("statement {} is not prepared", i_prepared_statement))
+        };
+        if trace {
+            let mut prepared = prepared.clone();
+            prepared.set_tracing(true);
+            Ok(Cow::Owned(prepared))
+        } else {
+            Ok(Cow::Borrowed(prepared))
+        }
+    }
+
+    /// Session details and events from `system_traces`. The query parameters are left out,
+    /// Scylla records the bound values there.
+    pub async fn tracing_details(&self, i_tracing_id: &Uuid) -> Result<serde_json::Value, anyhow::Error> {
+        let info = self.db_session.get_tracing_info(i_tracing_id).await?;
+        let events: Vec<serde_json::Value> = info.events
+            .iter()
+            .map(|e| json!({
+                "activity": e.activity,
+                "source": e.source.map(|v| v.to_string()),
+                "source_elapsed_us": e.source_elapsed,
+                "thread": e.thread
+            }))
+            .collect();
+        Ok(json!({
+            "coordinator": info.coordinator.map(|v| v.to_string()),
+            "duration_us": info.duration,
+            "request": info.request,
+            "events": events
+        }))
+    }
+
+    /// Local DC replica the token-aware policy routes the statement to, the driver does not
+    /// report the node that actually coordinated it. Only known for prepared statements, it
+    /// costs a token computation and a ring lookup so it is only called for slow operations.
+    pub fn estimate_coordinator(&self, i_prepared_statement: &str, i_execution: &ExecutionInfo) -> Option<String> {
+        let i_values = i_execution.routing_values.as_ref()?;
+        let i_prepared = self.p_queries.get(i_prepared_statement)?;
+        if !i_prepared.is_token_aware() {
+            return None
+        }
+        let partition_key = i_prepared.compute_partition_key(i_values).ok()?;
+        let token = Murmur3Partitioner::hash(partition_key);
+        let replicas = self.db_session.get_cluster_data().get_token_endpoints(i_prepared.get_keyspace_name()?, token);
+        replicas
+            .iter()
+            .find(|node| node.datacenter.as_deref() == Some(self.datacenter.as_str()))
+            .or(replicas.first())
+            .map(|node| node.address.to_string())
+    }
+}
+
+fn traced(mut i_query: Query, trace: bool) -> Query {
+    i_query.set_tracing(trace);
+    i_query
+}
+
+/// Adds the tracing session id of a traced operation to its result, and the `system_traces`
+/// details when `trace_events` is set. Failing to read them does not fail the operation.
+pub async fn attach_tracing(mut rj: serde_json::Value, execution: &ExecutionInfo, trace_events: bool, db_svc: &ScyllaService) -> serde_json::Value {
+    let tracing_id = match execution.tracing_id {
+        Some(v) => v,
+        None => return rj
+    };
+    rj["tracing_session_id"] = json!(tracing_id.to_string());
+    if trace_events {
+        rj["tracing"] = match db_svc.tracing_details(&tracing_id).await {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("Could not read tracing session {}: {}", tracing_id, e);
+                serde_json::Value::Null
+            }
+        };
+    }
+    rj
+}
+
+/// Marks the `cql_execute` span as failed, the error itself is logged by the handler.
+fn record_cql_result<T>(result: &Result<T, anyhow::Error>) {
+    if result.is_err() {
+        Span::current().record("otel.status_code", "ERROR");
     }
 }
 
 pub struct AppState {
-    pub db_svc: ScyllaService,
+    /// Session of the `default` cluster, its catalog attributes cover the statements of every cluster.
+    pub db_svc: Arc<ScyllaService>,
+    /// Every cluster by name, `default` included.
+    pub clusters: BTreeMap<String, Arc<ScyllaService>>,
+    pub authenticator: Authenticator,
+    pub cql_guard: GuardPolicy,
+    pub read_only: Arc<ReadOnlySwitch>,
+    pub rate_limiter: RateLimiter,
+    pub auditor: Auditor,
+    pub redactor: Redactor,
+    pub metrics: Metrics,
+    pub slow_queries: SlowQueryLog,
+    pub health: HealthChecker,
+    pub certificate: Option<Arc<ServerCertificate>>,
+    pub shutdown: Arc<Shutdown>,
     pub semaphore: Arc<Semaphore>,
     pub region: String
-}
\ No newline at end of file
+}
+impl AppState {
+    pub fn cluster(&self, i_name: &str) -> Result<&ScyllaService, serde_json::Value> {
+        match self.clusters.get(i_name) {
+            Some(v) => Ok(v),
+            None => Err(
+                erfn(400, format!("cluster {} is not configured", i_name), "unknown_cluster".to_string(), "unknown_cluster".to_string())
+            )
+        }
+    }
+
+    /// Session the catalog statement runs on, from its `cluster` attribute.
+    pub fn statement_cluster(&self, i_statement: &str) -> Result<&ScyllaService, serde_json::Value> {
+        let name = self.db_svc.p_queries_attributes
+            .get(i_statement)
+            .and_then(|a| a.get("cluster"))
+            .and_then(|c| c.as_str())
+            .unwrap_or(DEFAULT_CLUSTER);
+        self.cluster(name)
+    }
+}
@@ -0,0 +1,64 @@
+use actix_web::{
+    body::{BodySize, BoxBody, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    http::header::{ContentEncoding, ContentType, HeaderValue, CONTENT_ENCODING},
+    middleware::Next,
+    Error,
+    HttpResponse
+};
+use serde_json::json;
+use crate::common::erfn;
+use crate::request_id::RequestId;
+
+/// Sits inside `middleware::Compress`.
+///
+/// Requests whose `Content-Encoding` cannot be decoded are rejected with a 415, the decoding itself
+/// is done by the `web::Bytes` extractor, which also applies the `PayloadConfig` limit to the
+/// decompressed size.
+/// When `enabled`, responses smaller than `min_size` are marked as `identity` so that `Compress`
+/// leaves them alone. Without compression the responses are not touched.
+pub async fn negotiate(
+    request: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+    enabled: bool,
+    min_size: usize
+) -> Result<ServiceResponse<BoxBody>, Error> {
+
+    if let Some(encoding) = request.headers().get(CONTENT_ENCODING) {
+        let is_supported = encoding
+            .to_str()
+            .ok()
+            .and_then(|x| x.parse::<ContentEncoding>().ok())
+            .is_some();
+        if !is_supported {
+            let mut e = erfn(
+                415,
+                "unsupported_content_encoding".to_string(),
+                "unsupported_content_encoding".to_string(),
+                format!("content_encoding_{}_not_supported", encoding.to_str().unwrap_or(""))
+            );
+            e["request_id"] = json!(RequestId::from_request(request.request()));
+            return Ok(request.into_response(
+                HttpResponse::UnsupportedMediaType()
+                    .insert_header(ContentType::json())
+                    .body(e.to_string())
+            ));
+        }
+    }
+
+    let mut response = next.call(request).await?;
+
+    if !enabled {
+        return Ok(response.map_into_boxed_body())
+    }
+
+    if let BodySize::Sized(size) = response.response().body().size() {
+        if size < min_size as u64 {
+            response
+                .headers_mut()
+                .insert(CONTENT_ENCODING, HeaderValue::from_static("identity"));
+        }
+    }
+
+    Ok(response.map_into_boxed_body())
+}
@@ -0,0 +1,524 @@
+//! A light CQL tokenizer and classifier, used to apply guardrails to `/v2/direct_statement`
+//! before the statement reaches Scylla.
+//! It only understands what it needs to find the statement kind and its targets, everything
+//! else is skipped.
+
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    QuotedName(String),
+    Literal,
+    Symbol(char),
+}
+
+impl Token {
+    fn is_word(&self, i_word: &str) -> bool {
+        match self {
+            Token::Word(w) => w.eq_ignore_ascii_case(i_word),
+            _ => false
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StatementKind {
+    Select,
+    Insert,
+    Update,
+    Delete,
+    Batch,
+    Ddl,
+    Permission,
+    Use,
+    Other,
+}
+
+impl fmt::Display for StatementKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            StatementKind::Select => "SELECT",
+            StatementKind::Insert => "INSERT",
+            StatementKind::Update => "UPDATE",
+            StatementKind::Delete => "DELETE",
+            StatementKind::Batch => "BATCH",
+            StatementKind::Ddl => "DDL",
+            StatementKind::Permission => "PERMISSION",
+            StatementKind::Use => "USE",
+            StatementKind::Other => "OTHER",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Target {
+    pub keyspace: Option<String>,
+    pub table: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ClassifiedStatement {
+    pub kind: StatementKind,
+    pub targets: Vec<Target>,
+    pub has_limit: bool,
+    pub allow_filtering: bool,
+    pub statements_number: usize,
+}
+
+impl ClassifiedStatement {
+    pub fn is_read(&self) -> bool {
+        self.kind == StatementKind::Select
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CqlError {
+    pub message: String,
+}
+
+fn tokenize(i_statement: &str) -> Result<Vec<Token>, CqlError> {
+    let chars: Vec<char> = i_statement.chars().collect();
+    let mut tokens = vec![];
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+
+        if c.is_whitespace() {
+            i += 1;
+        } else if (c == '-' && next == Some('-')) || (c == '/' && next == Some('/')) {
+            while i < chars.len() && chars[i] != '\n' {
+                i += 1;
+            }
+        } else if c == '/' && next == Some('*') {
+            i += 2;
+            while i < chars.len() && !(chars[i] == '*' && chars.get(i + 1) == Some(&'/')) {
+                i += 1;
+            }
+            if i >= chars.len() {
+                return Err(CqlError { message: "unterminated comment".to_string() });
+            }
+            i += 2;
+        } else if c == '\'' {
+            i += 1;
+            loop {
+                match (chars.get(i), chars.get(i + 1)) {
+                    (Some('\''), Some('\'')) => i += 2,
+                    (Some('\''), _) => break,
+                    (Some(_), _) => i += 1,
+                    (None, _) => return Err(CqlError { message: "unterminated string literal".to_string() })
+                }
+            }
+            i += 1;
+            tokens.push(Token::Literal);
+        } else if c == '$' && next == Some('$') {
+            i += 2;
+            while i < chars.len() && !(chars[i] == '$' && chars.get(i + 1) == Some(&'$')) {
+                i += 1;
+            }
+            if i >= chars.len() {
+                return Err(CqlError { message: "unterminated $$ literal".to_string() });
+            }
+            i += 2;
+            tokens.push(Token::Literal);
+        } else if c == '"' {
+            let mut name = String::new();
+            i += 1;
+            loop {
+                match (chars.get(i), chars.get(i + 1)) {
+                    (Some('"'), Some('"')) => {
+                        name.push('"');
+                        i += 2;
+                    },
+                    (Some('"'), _) => break,
+                    (Some(x), _) => {
+                        name.push(*x);
+                        i += 1;
+                    },
+                    (None, _) => return Err(CqlError { message: "unterminated quoted name".to_string() })
+                }
+            }
+            i += 1;
+            tokens.push(Token::QuotedName(name));
+        } else if c.is_ascii_digit() {
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '.' || chars[i] == '-') {
+                i += 1;
+            }
+            tokens.push(Token::Literal);
+        } else if c.is_alphabetic() || c == '_' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            tokens.push(Token::Word(chars[start..i].iter().collect()));
+        } else {
+            tokens.push(Token::Symbol(c));
+            i += 1;
+        }
+    }
+
+    Ok(tokens)
+}
+
+/// Reads `name` or `keyspace.name` at `i_pos`, unquoted names are case insensitive.
+fn read_qualified_name(i_tokens: &[Token], i_pos: usize) -> Option<Target> {
+    let name_at = |pos: usize| match i_tokens.get(pos) {
+        Some(Token::Word(w)) => Some(w.to_lowercase()),
+        Some(Token::QuotedName(n)) => Some(n.clone()),
+        _ => None
+    };
+    let first = name_at(i_pos)?;
+    if i_tokens.get(i_pos + 1) == Some(&Token::Symbol('.')) {
+        let second = name_at(i_pos + 2)?;
+        Some(Target { keyspace: Some(first), table: Some(second) })
+    } else {
+        Some(Target { keyspace: None, table: Some(first) })
+    }
+}
+
+fn position_of(i_tokens: &[Token], i_word: &str) -> Option<usize> {
+    i_tokens.iter().position(|t| t.is_word(i_word))
+}
+
+fn target_after(i_tokens: &[Token], i_word: &str) -> Vec<Target> {
+    position_of(i_tokens, i_word)
+        .and_then(|p| read_qualified_name(i_tokens, p + 1))
+        .into_iter()
+        .collect()
+}
+
+const SCHEMA_OBJECT_WORDS: [&str; 14] = [
+    "TABLE", "COLUMNFAMILY", "INDEX", "TYPE", "MATERIALIZED", "VIEW", "FUNCTION",
+    "AGGREGATE", "TRIGGER", "OR", "REPLACE", "IF", "NOT", "EXISTS",
+];
+
+fn ddl_targets(i_tokens: &[Token]) -> Vec<Target> {
+    if i_tokens.get(1).map(|t| t.is_word("KEYSPACE")).unwrap_or(false) {
+        let mut pos = 2;
+        while i_tokens.get(pos).map(|t| SCHEMA_OBJECT_WORDS.iter().any(|w| t.is_word(w))).unwrap_or(false) {
+            pos += 1;
+        }
+        return read_qualified_name(i_tokens, pos)
+            .map(|t| Target { keyspace: t.table, table: None })
+            .into_iter()
+            .collect()
+    }
+    if position_of(i_tokens, "ON").is_some() {
+        return target_after(i_tokens, "ON")
+    }
+    let mut pos = 1;
+    while i_tokens.get(pos).map(|t| t.is_word("CUSTOM") || SCHEMA_OBJECT_WORDS.iter().any(|w| t.is_word(w))).unwrap_or(false) {
+        pos += 1;
+    }
+    read_qualified_name(i_tokens, pos).into_iter().collect()
+}
+
+fn permission_targets(i_tokens: &[Token]) -> Vec<Target> {
+    let on = match position_of(i_tokens, "ON") {
+        Some(v) => v,
+        None => return vec![]
+    };
+    match i_tokens.get(on + 1) {
+        Some(t) if t.is_word("ALL") => vec![],
+        Some(t) if t.is_word("KEYSPACE") => read_qualified_name(i_tokens, on + 2)
+            .map(|t| Target { keyspace: t.table, table: None })
+            .into_iter()
+            .collect(),
+        Some(t) if t.is_word("TABLE") => read_qualified_name(i_tokens, on + 2).into_iter().collect(),
+        _ => read_qualified_name(i_tokens, on + 1).into_iter().collect()
+    }
+}
+
+fn dml_targets(i_tokens: &[Token]) -> Vec<Target> {
+    let mut targets = vec![];
+    for (idx, token) in i_tokens.iter().enumerate() {
+        if token.is_word("INSERT") && i_tokens.get(idx + 1).map(|t| t.is_word("INTO")).unwrap_or(false) {
+            targets.extend(read_qualified_name(i_tokens, idx + 2));
+        } else if token.is_word("UPDATE") {
+            targets.extend(read_qualified_name(i_tokens, idx + 1));
+        } else if token.is_word("DELETE") {
+            targets.extend(target_after(&i_tokens[idx..], "FROM"));
+        }
+    }
+    targets
+}
+
+fn classify_tokens(i_tokens: &[Token]) -> ClassifiedStatement {
+    let first = i_tokens.first();
+    let second_is = |w: &str| i_tokens.get(1).map(|t| t.is_word(w)).unwrap_or(false);
+    let is = |w: &str| first.map(|t| t.is_word(w)).unwrap_or(false);
+
+    let (kind, targets) = if is("SELECT") {
+        (StatementKind::Select, target_after(i_tokens, "FROM"))
+    } else if is("INSERT") || is("UPDATE") || is("DELETE") {
+        let kind = if is("INSERT") {
+            StatementKind::Insert
+        } else if is("UPDATE") {
+            StatementKind::Update
+        } else {
+            StatementKind::Delete
+        };
+        (kind, dml_targets(i_tokens))
+    } else if is("BEGIN") {
+        (StatementKind::Batch, dml_targets(i_tokens))
+    } else if (is("CREATE") || is("ALTER") || is("DROP")) && (second_is("ROLE") || second_is("USER")) {
+        (StatementKind::Permission, vec![])
+    } else if is("CREATE") || is("ALTER") || is("DROP") || is("TRUNCATE") {
+        (StatementKind::Ddl, ddl_targets(i_tokens))
+    } else if is("GRANT") || is("REVOKE") || is("LIST") {
+        (StatementKind::Permission, permission_targets(i_tokens))
+    } else if is("USE") {
+        let targets = read_qualified_name(i_tokens, 1)
+            .map(|t| Target { keyspace: t.table, table: None })
+            .into_iter()
+            .collect();
+        (StatementKind::Use, targets)
+    } else {
+        (StatementKind::Other, vec![])
+    };
+
+    let has_limit = i_tokens.iter().enumerate().any(|(idx, t)| {
+        t.is_word("LIMIT") && !(idx > 0 && i_tokens[idx - 1].is_word("PARTITION"))
+    });
+    let allow_filtering = i_tokens.windows(2).any(|w| w[0].is_word("ALLOW") && w[1].is_word("FILTERING"));
+
+    ClassifiedStatement {
+        kind,
+        targets,
+        has_limit,
+        allow_filtering,
+        statements_number: 1,
+    }
+}
+
+/// Splits on `;`, keeping `BEGIN BATCH ... APPLY BATCH` together.
+fn split_statements(i_tokens: Vec<Token>) -> Vec<Vec<Token>> {
+    let mut statements = vec![];
+    let mut current: Vec<Token> = vec![];
+    let mut in_batch = false;
+
+    for token in i_tokens {
+        if current.is_empty() && token.is_word("BEGIN") {
+            in_batch = true;
+        }
+        if token == Token::Symbol(';') && !in_batch {
+            if !current.is_empty() {
+                statements.push(std::mem::take(&mut current));
+            }
+            continue;
+        }
+        if in_batch && token.is_word("BATCH") && current.last().map(|t| t.is_word("APPLY")).unwrap_or(false) {
+            in_batch = false;
+        }
+        current.push(token);
+    }
+    if !current.is_empty() {
+        statements.push(current);
+    }
+    statements
+}
+
+pub fn classify(i_statement: &str) -> Result<ClassifiedStatement, CqlError> {
+    let statements = split_statements(tokenize(i_statement)?);
+    let first = match statements.first() {
+        Some(v) => v,
+        None => return Err(CqlError { message: "empty statement".to_string() })
+    };
+    let mut classified = classify_tokens(first);
+    classified.statements_number = statements.len();
+    Ok(classified)
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct GuardPolicy {
+    pub deny_ddl: bool,
+    pub deny_permission_statements: bool,
+    pub deny_allow_filtering: bool,
+    pub require_limit: bool,
+    pub allowed_keyspaces: Option<Vec<String>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct GuardViolation {
+    pub rule: String,
+    pub message: String,
+}
+
+fn violation(i_rule: &str, i_message: String) -> Result<(), GuardViolation> {
+    Err(GuardViolation { rule: i_rule.to_string(), message: i_message })
+}
+
+impl GuardPolicy {
+    pub fn check(&self, i_statement: &ClassifiedStatement) -> Result<(), GuardViolation> {
+        if i_statement.statements_number > 1 {
+            return violation("deny_multiple_statements", format!("{} statements found, only one is allowed", i_statement.statements_number))
+        }
+        if self.deny_ddl && matches!(i_statement.kind, StatementKind::Ddl | StatementKind::Use) {
+            return violation("deny_ddl", format!("{} statements are not allowed", i_statement.kind))
+        }
+        if self.deny_permission_statements && i_statement.kind == StatementKind::Permission {
+            return violation("deny_permission_statements", "permission statements are not allowed".to_string())
+        }
+        if self.deny_allow_filtering && i_statement.allow_filtering {
+            return violation("deny_allow_filtering", "ALLOW FILTERING is not allowed".to_string())
+        }
+        if self.require_limit && i_statement.kind == StatementKind::Select && !i_statement.has_limit {
+            return violation("require_limit", "SELECT statements must have a LIMIT".to_string())
+        }
+        if let Some(allowed) = &self.allowed_keyspaces {
+            if i_statement.targets.is_empty() {
+                return violation("allowed_keyspaces", format!("the keyspace of this {} statement can't be determined", i_statement.kind))
+            }
+            for target in &i_statement.targets {
+                match &target.keyspace {
+                    Some(k) if allowed.iter().any(|a| a == k) => (),
+                    Some(k) => return violation("allowed_keyspaces", format!("keyspace {} is not allowed", k)),
+                    None => return violation("allowed_keyspaces", format!("table {} must be qualified with its keyspace", target.table.as_deref().unwrap_or("")))
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> GuardPolicy {
+        GuardPolicy {
+            deny_ddl: true,
+            deny_permission_statements: true,
+            deny_allow_filtering: true,
+            require_limit: true,
+            allowed_keyspaces: Some(vec!["shop".to_string()]),
+        }
+    }
+
+    fn check(i_statement: &str) -> Result<(), String> {
+        let classified = classify(i_statement).map_err(|e| e.message)?;
+        policy().check(&classified).map_err(|v| v.rule)
+    }
+
+    fn target(i_keyspace: Option<&str>, i_table: Option<&str>) -> Target {
+        Target { keyspace: i_keyspace.map(|k| k.to_string()), table: i_table.map(|t| t.to_string()) }
+    }
+
+    #[test]
+    fn keywords_in_literals_are_ignored() {
+        let classified = classify("SELECT * FROM shop.orders WHERE note = 'DROP TABLE shop.orders; ALLOW FILTERING' LIMIT 10").unwrap();
+        assert_eq!(classified.kind, StatementKind::Select);
+        assert_eq!(classified.statements_number, 1);
+        assert!(!classified.allow_filtering);
+        assert_eq!(check("SELECT * FROM shop.orders WHERE note = 'it''s; DROP KEYSPACE shop' LIMIT 1"), Ok(()));
+        assert_eq!(check("SELECT * FROM shop.orders WHERE note = $$ ; DROP TABLE shop.orders $$ LIMIT 1"), Ok(()));
+    }
+
+    #[test]
+    fn keywords_in_quoted_names_are_ignored() {
+        let classified = classify(r#"SELECT "DROP", "a""; DELETE" FROM shop."Orders" LIMIT 1"#).unwrap();
+        assert_eq!(classified.kind, StatementKind::Select);
+        assert_eq!(classified.statements_number, 1);
+        assert_eq!(classified.targets, vec![target(Some("shop"), Some("Orders"))]);
+    }
+
+    #[test]
+    fn keywords_in_comments_are_ignored() {
+        assert_eq!(check("SELECT * FROM shop.orders -- ; DROP TABLE shop.orders\nLIMIT 1"), Ok(()));
+        assert_eq!(check("SELECT * FROM shop.orders // ; DROP TABLE shop.orders\nLIMIT 1"), Ok(()));
+        assert_eq!(check("SELECT * FROM shop.orders /* ; DROP TABLE shop.orders ALLOW FILTERING */ LIMIT 1"), Ok(()));
+        let classified = classify("/* SELECT */ DELETE FROM shop.orders WHERE id = 1").unwrap();
+        assert_eq!(classified.kind, StatementKind::Delete);
+    }
+
+    #[test]
+    fn statement_kinds() {
+        let kind = |s: &str| classify(s).unwrap().kind;
+        assert_eq!(kind("select * from shop.orders"), StatementKind::Select);
+        assert_eq!(kind("INSERT INTO shop.orders (id) VALUES (1)"), StatementKind::Insert);
+        assert_eq!(kind("UPDATE shop.orders SET a = 1 WHERE id = 1"), StatementKind::Update);
+        assert_eq!(kind("DELETE FROM shop.orders WHERE id = 1"), StatementKind::Delete);
+        assert_eq!(kind("TRUNCATE shop.orders"), StatementKind::Ddl);
+        assert_eq!(kind("CREATE TABLE IF NOT EXISTS shop.t (id int PRIMARY KEY)"), StatementKind::Ddl);
+        assert_eq!(kind("CREATE ROLE admin"), StatementKind::Permission);
+        assert_eq!(kind("GRANT SELECT ON KEYSPACE shop TO reporting"), StatementKind::Permission);
+        assert_eq!(kind("USE shop"), StatementKind::Use);
+        assert!(!classify("INSERT INTO shop.orders (id) VALUES (1)").unwrap().is_read());
+    }
+
+    #[test]
+    fn multiple_statements_are_refused() {
+        let classified = classify("SELECT * FROM shop.orders LIMIT 1; DROP TABLE shop.orders").unwrap();
+        assert_eq!(classified.statements_number, 2);
+        assert_eq!(check("SELECT * FROM shop.orders LIMIT 1; DROP TABLE shop.orders"), Err("deny_multiple_statements".to_string()));
+        assert_eq!(check("SELECT * FROM shop.orders LIMIT 1;;"), Ok(()));
+    }
+
+    #[test]
+    fn batches() {
+        let batch = "BEGIN BATCH INSERT INTO shop.orders (id) VALUES (1); UPDATE shop.stock SET n = 1 WHERE id = 1; DELETE FROM shop.carts WHERE id = 1; APPLY BATCH";
+        let classified = classify(batch).unwrap();
+        assert_eq!(classified.kind, StatementKind::Batch);
+        assert_eq!(classified.statements_number, 1);
+        assert!(!classified.is_read());
+        assert_eq!(classified.targets, vec![
+            target(Some("shop"), Some("orders")),
+            target(Some("shop"), Some("stock")),
+            target(Some("shop"), Some("carts"))
+        ]);
+
+        // A read inside a batch doesn't make the batch a read.
+        let mixed = classify("BEGIN BATCH SELECT * FROM shop.orders; INSERT INTO shop.orders (id) VALUES (1); APPLY BATCH").unwrap();
+        assert_eq!(mixed.kind, StatementKind::Batch);
+        assert!(!mixed.is_read());
+
+        assert_eq!(check(&format!("{}; DROP TABLE shop.orders", batch)), Err("deny_multiple_statements".to_string()));
+        assert_eq!(
+            check("BEGIN BATCH INSERT INTO shop.orders (id) VALUES (1); INSERT INTO other.orders (id) VALUES (1); APPLY BATCH"),
+            Err("allowed_keyspaces".to_string())
+        );
+    }
+
+    #[test]
+    fn per_partition_limit_is_not_a_limit() {
+        assert!(!classify("SELECT * FROM shop.orders PER PARTITION LIMIT 1").unwrap().has_limit);
+        assert!(classify("SELECT * FROM shop.orders PER PARTITION LIMIT 1 LIMIT 10").unwrap().has_limit);
+        assert!(classify("SELECT * FROM shop.orders LIMIT 10").unwrap().has_limit);
+        assert_eq!(check("SELECT * FROM shop.orders PER PARTITION LIMIT 1"), Err("require_limit".to_string()));
+    }
+
+    #[test]
+    fn allowed_keyspaces() {
+        assert_eq!(check("SELECT * FROM shop.orders LIMIT 1"), Ok(()));
+        assert_eq!(check("SELECT * FROM SHOP.orders LIMIT 1"), Ok(()));
+        assert_eq!(check("SELECT * FROM orders LIMIT 1"), Err("allowed_keyspaces".to_string()));
+        assert_eq!(check("SELECT * FROM billing.invoices LIMIT 1"), Err("allowed_keyspaces".to_string()));
+        assert_eq!(check(r#"SELECT * FROM "Shop".orders LIMIT 1"#), Err("allowed_keyspaces".to_string()));
+        assert_eq!(check("DELETE FROM orders WHERE id = 1"), Err("allowed_keyspaces".to_string()));
+        assert_eq!(check("SELECT now() LIMIT 1"), Err("allowed_keyspaces".to_string()));
+    }
+
+    #[test]
+    fn guardrails() {
+        assert_eq!(check("DROP TABLE shop.orders"), Err("deny_ddl".to_string()));
+        assert_eq!(check("USE shop"), Err("deny_ddl".to_string()));
+        assert_eq!(check("GRANT SELECT ON shop.orders TO reporting"), Err("deny_permission_statements".to_string()));
+        assert_eq!(check("SELECT * FROM shop.orders WHERE a = 1 LIMIT 1 ALLOW FILTERING"), Err("deny_allow_filtering".to_string()));
+        assert!(GuardPolicy::default().check(&classify("DROP KEYSPACE shop").unwrap()).is_ok());
+    }
+
+    #[test]
+    fn parse_failures_are_refused() {
+        assert!(classify("").is_err());
+        assert!(classify("  ;  ").is_err());
+        assert!(classify("-- only a comment").is_err());
+        assert!(classify("SELECT * FROM shop.orders WHERE a = 'unterminated").is_err());
+        assert!(classify("SELECT * FROM shop.orders WHERE a = $$ unterminated").is_err());
+        assert!(classify(r#"SELECT * FROM shop."unterminated"#).is_err());
+        assert!(classify("SELECT * FROM shop.orders /* unterminated").is_err());
+        assert!(check("DROP TABLE shop.orders; SELECT 'x").is_err());
+    }
+}
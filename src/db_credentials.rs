@@ -0,0 +1,143 @@
+use async_trait::async_trait;
+use scylla::authentication::{AuthError, AuthenticatorProvider, AuthenticatorSession};
+use std::fmt;
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// Scylla user and password, from the configuration or from files such as a mounted Kubernetes secret.
+/// The files are re-read when they change and every new connection authenticates with the current values,
+/// open connections stay authenticated with the values they started with.
+pub struct DbCredentials {
+    user_file: Option<String>,
+    password_file: Option<String>,
+    current: RwLock<(String, String)>,
+    rotations: AtomicU64,
+}
+
+/// Never shows the password.
+impl fmt::Debug for DbCredentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DbCredentials")
+            .field("user", &self.user())
+            .field("user_file", &self.user_file)
+            .field("password_file", &self.password_file)
+            .finish()
+    }
+}
+
+fn read_secret(i_path: &str) -> Result<String, anyhow::Error> {
+    let content = std::fs::read_to_string(i_path)
+        .map_err(|e| anyhow::anyhow!("{} can't be read: {}", i_path, e))?;
+    Ok(content.trim_end_matches(['\r', '\n']).to_string())
+}
+
+impl DbCredentials {
+    /// `i_user_file` and `i_password_file`, when given, take precedence over `i_user` and `i_password`.
+    pub fn load(
+        i_user: &str,
+        i_password: &str,
+        i_user_file: Option<String>,
+        i_password_file: Option<String>
+    ) -> Result<Arc<DbCredentials>, anyhow::Error> {
+        let user = match &i_user_file {
+            Some(path) => read_secret(path)?,
+            None => i_user.to_string()
+        };
+        let password = match &i_password_file {
+            Some(path) => read_secret(path)?,
+            None => i_password.to_string()
+        };
+        Ok(Arc::new(DbCredentials {
+            user_file: i_user_file,
+            password_file: i_password_file,
+            current: RwLock::new((user, password)),
+            rotations: AtomicU64::new(0),
+        }))
+    }
+
+    pub fn user(&self) -> String {
+        self.current.read().unwrap().0.clone()
+    }
+
+    pub fn rotations(&self) -> u64 {
+        self.rotations.load(Ordering::SeqCst)
+    }
+
+    /// A file that can't be read, for example while the secret is being replaced, keeps the
+    /// current value until the next poll.
+    fn reload(&self) {
+        let mut next = self.current.read().unwrap().clone();
+        if let Some(path) = &self.user_file {
+            match read_secret(path) {
+                Ok(v) => next.0 = v,
+                Err(e) => {
+                    warn!("Scylla credentials not reloaded: {}", e);
+                    return
+                }
+            }
+        }
+        if let Some(path) = &self.password_file {
+            match read_secret(path) {
+                Ok(v) => next.1 = v,
+                Err(e) => {
+                    warn!("Scylla credentials not reloaded: {}", e);
+                    return
+                }
+            }
+        }
+        let mut current = self.current.write().unwrap();
+        if *current != next {
+            *current = next;
+            self.rotations.fetch_add(1, Ordering::SeqCst);
+            info!("Scylla credentials rotated for user {}, new connections use them", current.0);
+        }
+    }
+
+    /// Polls the files, contents are compared since secret mounts swap symlinks rather than
+    /// writing the files in place.
+    pub fn watch(credentials: Arc<DbCredentials>, i_poll_interval: Duration) {
+        if credentials.user_file.is_none() && credentials.password_file.is_none() {
+            return
+        }
+        info!("Watching Scylla credentials files");
+        actix_web::rt::spawn(async move {
+            let mut interval = actix_web::rt::time::interval(i_poll_interval);
+            loop {
+                interval.tick().await;
+                credentials.reload();
+            }
+        });
+    }
+}
+
+struct PlainTextSession;
+
+#[async_trait]
+impl AuthenticatorSession for PlainTextSession {
+    async fn evaluate_challenge(&mut self, _token: Option<&[u8]>) -> Result<Option<Vec<u8>>, AuthError> {
+        Err("Challenges are not expected during PlainTextAuthentication".to_string())
+    }
+
+    async fn success(&mut self, _token: Option<&[u8]>) -> Result<(), AuthError> {
+        Ok(())
+    }
+}
+
+/// Same exchange as the driver `PlainTextAuthenticator`, with the credentials of the moment.
+#[async_trait]
+impl AuthenticatorProvider for DbCredentials {
+    async fn start_authentication_session(
+        &self,
+        _authenticator_name: &str
+    ) -> Result<(Option<Vec<u8>>, Box<dyn AuthenticatorSession>), AuthError> {
+        let (user, password) = self.current.read().unwrap().clone();
+        let mut response = Vec::with_capacity(user.len() + password.len() + 2);
+        response.push(0);
+        response.extend_from_slice(user.as_bytes());
+        response.push(0);
+        response.extend_from_slice(password.as_bytes());
+        Ok((Some(response), Box::new(PlainTextSession)))
+    }
+}
@@ -0,0 +1,171 @@
+use serde_json::json;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use actix_web::rt::time::timeout;
+use tokio::sync::Mutex as AsyncMutex;
+use futures_util::future::join_all;
+use crate::app_config::DEFAULT_CLUSTER;
+use crate::common::ScyllaService;
+
+pub const CHECK_PASS: &str = "pass";
+pub const CHECK_FAIL: &str = "fail";
+
+/// Outcome of the readiness checks, `details` is what the probe endpoints return.
+#[derive(Debug, Clone)]
+pub struct Readiness {
+    pub ready: bool,
+    pub details: serde_json::Value,
+}
+
+/// Runs the readiness checks against the cluster and keeps the result for `cache_ttl`,
+/// so frequent probes from several sources don't turn into load on Scylla.
+pub struct HealthChecker {
+    cache_ttl: Duration,
+    query_timeout: Duration,
+    cached: Mutex<Option<(Instant, Readiness)>>,
+    /// Held while the checks run, concurrent probes wait for that run instead of starting their own.
+    refreshing: AsyncMutex<()>,
+    started: AtomicBool,
+}
+
+impl HealthChecker {
+    pub fn new(cache_ttl: Duration, query_timeout: Duration) -> HealthChecker {
+        HealthChecker {
+            cache_ttl,
+            query_timeout,
+            cached: Mutex::new(None),
+            refreshing: AsyncMutex::new(()),
+            started: AtomicBool::new(false),
+        }
+    }
+
+    /// Ready when every cluster passes its checks. The checks of the `default` cluster stay at the
+    /// top level, the ones of the other named clusters go under `clusters`.
+    pub async fn readiness(&self, clusters: &BTreeMap<String, Arc<ScyllaService>>) -> Readiness {
+        if let Some(readiness) = self.fresh() {
+            return readiness
+        }
+        let _refreshing = self.refreshing.lock().await;
+        if let Some(readiness) = self.fresh() {
+            return readiness
+        }
+
+        let checked = join_all(clusters.values().map(|db_svc| self.check_cluster(db_svc))).await;
+        let ready = checked.iter().all(|(_, cluster_ready, _)| *cluster_ready);
+        if ready {
+            self.started.store(true, Ordering::SeqCst);
+        }
+        let mut details = json!({
+            "status": if ready { "ready" } else { "not_ready" }
+        });
+        for (name, cluster_ready, checks) in checked {
+            if name == DEFAULT_CLUSTER {
+                details["checks"] = checks;
+            } else {
+                details["clusters"][name] = json!({
+                    "status": if cluster_ready { "ready" } else { "not_ready" },
+                    "checks": checks
+                });
+            }
+        }
+        let readiness = Readiness {
+            ready,
+            details,
+        };
+
+        *self.cached.lock().unwrap() = Some((Instant::now(), readiness.clone()));
+        readiness
+    }
+
+    /// The cached result while it is younger than `cache_ttl`.
+    fn fresh(&self) -> Option<Readiness> {
+        match self.cached.lock().unwrap().as_ref() {
+            Some((checked_at, readiness)) if checked_at.elapsed() < self.cache_ttl => Some(readiness.clone()),
+            _ => None
+        }
+    }
+
+    /// True once the readiness checks have passed at least once.
+    pub fn has_started(&self) -> bool {
+        self.started.load(Ordering::SeqCst)
+    }
+
+    async fn check_cluster(&self, db_svc: &ScyllaService) -> (String, bool, serde_json::Value) {
+        let nodes = check_nodes(db_svc);
+        let query = self.check_query(db_svc).await;
+        let catalog = check_catalog(db_svc);
+
+        let ready = [&nodes, &query, &catalog].iter().all(|c| c["status"] == CHECK_PASS);
+        (db_svc.name.clone(), ready, json!({
+            "nodes": nodes,
+            "query": query,
+            "catalog": catalog
+        }))
+    }
+
+    async fn check_query(&self, db_svc: &ScyllaService) -> serde_json::Value {
+        let started = Instant::now();
+        match timeout(self.query_timeout, db_svc.ping()).await {
+            Ok(Ok(())) => json!({
+                "status": CHECK_PASS,
+                "latency_ms": started.elapsed().as_secs_f64() * 1000.0
+            }),
+            Ok(Err(e)) => json!({
+                "status": CHECK_FAIL,
+                "error": e.to_string()
+            }),
+            Err(_) => json!({
+                "status": CHECK_FAIL,
+                "error": format!("no answer within {} ms", self.query_timeout.as_millis())
+            })
+        }
+    }
+}
+
+/// Up and down nodes per datacenter, fails when no node of the local datacenter is up.
+fn check_nodes(db_svc: &ScyllaService) -> serde_json::Value {
+    let mut datacenters: BTreeMap<String, (u64, u64)> = BTreeMap::new();
+    for node in db_svc.db_session.get_cluster_data().get_nodes_info() {
+        let counts = datacenters
+            .entry(node.datacenter.clone().unwrap_or_else(|| "unknown".to_string()))
+            .or_default();
+        if node.is_down() {
+            counts.1 += 1;
+        } else {
+            counts.0 += 1;
+        }
+    }
+    let local_up = datacenters.get(&db_svc.datacenter).map(|c| c.0).unwrap_or(0);
+    json!({
+        "status": if local_up > 0 { CHECK_PASS } else { CHECK_FAIL },
+        "local_datacenter": db_svc.datacenter,
+        "datacenters": datacenters
+            .iter()
+            .map(|(dc, (up, down))| (dc.clone(), json!({ "up": up, "down": down })))
+            .collect::<serde_json::Map<String, serde_json::Value>>()
+    })
+}
+
+/// Every statement of the cluster marked `is_prepared` in the catalog has its prepared statement,
+/// `errors` has what Scylla answered to the ones it refused.
+fn check_catalog(db_svc: &ScyllaService) -> serde_json::Value {
+    let statements: Vec<(&String, &HashMap<String, serde_json::Value>)> = db_svc.p_queries_attributes
+        .iter()
+        .filter(|(_, attributes)| attributes["cluster"].as_str().unwrap_or(DEFAULT_CLUSTER) == db_svc.name)
+        .collect();
+    let missing: Vec<&String> = statements
+        .iter()
+        .filter(|(_, attributes)| attributes["is_prepared"].as_bool().unwrap_or(false))
+        .map(|(statement_id, _)| *statement_id)
+        .filter(|statement_id| !db_svc.p_queries.contains_key(*statement_id))
+        .collect();
+    json!({
+        "status": if missing.is_empty() { CHECK_PASS } else { CHECK_FAIL },
+        "statements": statements.len(),
+        "prepared": db_svc.p_queries.len(),
+        "missing": missing,
+        "errors": db_svc.prepare_errors.as_ref()
+    })
+}
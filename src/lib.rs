@@ -1,4 +1,21 @@
 pub mod app_config;
+pub mod audit;
+pub mod auth;
 pub mod common;
+pub mod compression;
+pub mod cql_classifier;
+pub mod db_credentials;
+pub mod health;
+pub mod listener;
+pub mod maintenance;
+pub mod metrics;
+pub mod rate_limit;
+pub mod redaction;
+pub mod request_id;
 pub mod service;
+pub mod shutdown;
+pub mod slow_query;
+pub mod telemetry;
+pub mod tls_certificate;
+pub mod topology;
 pub mod queries;
\ No newline at end of file
@@ -0,0 +1,187 @@
+use anyhow::anyhow;
+use crate::app_config::Config;
+
+pub const ROUTES_HEALTH: &str = "health";
+pub const ROUTES_API: &str = "api";
+pub const ROUTES_ADMIN: &str = "admin";
+pub const ROUTES_METRICS: &str = "metrics";
+pub const ALL_ROUTE_GROUPS: [&str; 4] = [ROUTES_HEALTH, ROUTES_API, ROUTES_ADMIN, ROUTES_METRICS];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ListenerKind {
+    Https,
+    Http,
+    Unix,
+}
+
+/// One address the proxy accepts requests on, with the route groups it serves.
+#[derive(Debug, Clone)]
+pub struct Listener {
+    pub kind: ListenerKind,
+    pub address: String,
+    pub route_groups: Vec<String>,
+    /// The spec listed its route groups with `?routes=`, otherwise it serves every group.
+    pub routes_specified: bool,
+}
+
+impl Listener {
+    /// `https://host:port`, `http://host:port` or `unix:///path/to/socket`, optionally followed
+    /// by `?routes=` and route groups joined with `+`, e.g. `http://127.0.0.1:9000?routes=admin+metrics`.
+    pub fn parse(i_spec: &str) -> Result<Listener, anyhow::Error> {
+        let spec = i_spec.trim();
+        let (location, route_groups, routes_specified) = match spec.split_once("?routes=") {
+            Some((location, groups)) => {
+                let groups: Vec<String> = groups.split('+').map(|g| g.trim().to_lowercase()).collect();
+                if let Some(unknown) = groups.iter().find(|g| !ALL_ROUTE_GROUPS.contains(&g.as_str())) {
+                    return Err(anyhow!("listener {}: unknown route group {}, expected some of {}", spec, unknown, ALL_ROUTE_GROUPS.join(", ")))
+                }
+                (location, groups, true)
+            },
+            None => (spec, ALL_ROUTE_GROUPS.iter().map(|g| g.to_string()).collect(), false)
+        };
+        let (kind, address) = match location.split_once("://") {
+            Some(("https", address)) => (ListenerKind::Https, address),
+            Some(("http", address)) => (ListenerKind::Http, address),
+            Some(("unix", address)) => (ListenerKind::Unix, address),
+            _ => return Err(anyhow!("listener {}: expected https://, http:// or unix://", spec))
+        };
+        if address.is_empty() {
+            return Err(anyhow!("listener {}: missing address", spec))
+        }
+        Ok(Listener {
+            kind,
+            address: address.to_string(),
+            route_groups,
+            routes_specified,
+        })
+    }
+
+    pub fn serves(&self, i_route_group: &str) -> bool {
+        self.route_groups.iter().any(|g| g == i_route_group)
+    }
+
+    pub fn url(&self) -> String {
+        let scheme = match self.kind {
+            ListenerKind::Https => "https",
+            ListenerKind::Http => "http",
+            ListenerKind::Unix => "unix",
+        };
+        format!("{}://{}", scheme, self.address)
+    }
+}
+
+/// `LISTENERS` when set, a single HTTPS listener on `HOST`:`PORT` serving every route group otherwise.
+/// Once a listener lists `admin` in its `?routes=`, the listeners without `?routes=` no longer
+/// serve the admin routes, they stay on the dedicated listener only.
+pub fn from_config(config: &Config) -> Result<Vec<Listener>, anyhow::Error> {
+    let listeners = match &config.listeners {
+        Some(specs) => {
+            let mut listeners = specs
+                .iter()
+                .filter(|s| !s.trim().is_empty())
+                .map(|s| Listener::parse(s))
+                .collect::<Result<Vec<Listener>, anyhow::Error>>()?;
+            let dedicated_admin = listeners
+                .iter()
+                .any(|listener| listener.routes_specified && listener.serves(ROUTES_ADMIN));
+            if dedicated_admin {
+                for listener in listeners.iter_mut().filter(|l| !l.routes_specified) {
+                    listener.route_groups.retain(|g| g != ROUTES_ADMIN);
+                }
+            }
+            listeners
+        },
+        None => vec![Listener {
+            kind: ListenerKind::Https,
+            address: format!("{}:{}", config.host, config.port),
+            route_groups: ALL_ROUTE_GROUPS.iter().map(|g| g.to_string()).collect(),
+            routes_specified: false,
+        }]
+    };
+    if listeners.is_empty() {
+        return Err(anyhow!("LISTENERS has no listener"))
+    }
+    Ok(listeners)
+}
+
+/// `WORKERS`, twice the number of CPUs by default.
+pub fn workers(config: &Config) -> Result<usize, anyhow::Error> {
+    match config.workers {
+        Some(0) => Err(anyhow!("WORKERS must be at least 1")),
+        Some(n) => Ok(n),
+        None => Ok(num_cpus::get() * 2)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(i_listeners: Option<&[&str]>) -> Config {
+        serde_json::from_value(serde_json::json!({
+            "region": "test",
+            "db_dc": "dc1",
+            "parallel_files": 1,
+            "db_parallelism": 1,
+            "payload_max_size": 1024,
+            "listeners": i_listeners
+        })).unwrap()
+    }
+
+    #[test]
+    fn parse_without_routes_serves_every_group() {
+        let listener = Listener::parse(" https://0.0.0.0:8443 ").unwrap();
+        assert_eq!(listener.kind, ListenerKind::Https);
+        assert_eq!(listener.address, "0.0.0.0:8443");
+        assert_eq!(listener.route_groups, ALL_ROUTE_GROUPS.to_vec());
+        assert!(!listener.routes_specified);
+        assert_eq!(listener.url(), "https://0.0.0.0:8443");
+    }
+
+    #[test]
+    fn parse_with_routes() {
+        let listener = Listener::parse("http://127.0.0.1:9000?routes=Admin+ metrics").unwrap();
+        assert_eq!(listener.kind, ListenerKind::Http);
+        assert_eq!(listener.address, "127.0.0.1:9000");
+        assert_eq!(listener.route_groups, vec![ROUTES_ADMIN, ROUTES_METRICS]);
+        assert!(listener.routes_specified);
+        assert!(listener.serves(ROUTES_ADMIN));
+        assert!(!listener.serves(ROUTES_API));
+
+        let listener = Listener::parse("unix:///run/proxy.sock?routes=api").unwrap();
+        assert_eq!(listener.kind, ListenerKind::Unix);
+        assert_eq!(listener.address, "/run/proxy.sock");
+        assert_eq!(listener.route_groups, vec![ROUTES_API]);
+    }
+
+    #[test]
+    fn parse_rejects_invalid_specs() {
+        assert!(Listener::parse("http://127.0.0.1:9000?routes=admin+debug").is_err());
+        assert!(Listener::parse("http://127.0.0.1:9000?routes=").is_err());
+        assert!(Listener::parse("ftp://127.0.0.1:9000").is_err());
+        assert!(Listener::parse("127.0.0.1:9000").is_err());
+        assert!(Listener::parse("https://").is_err());
+    }
+
+    #[test]
+    fn dedicated_admin_listener_takes_the_admin_routes() {
+        let listeners = from_config(&config(Some(&["https://0.0.0.0:8443", "http://127.0.0.1:9000?routes=admin+metrics"]))).unwrap();
+        assert!(!listeners[0].serves(ROUTES_ADMIN));
+        assert!(listeners[0].serves(ROUTES_API));
+        assert!(listeners[0].serves(ROUTES_METRICS));
+        assert!(listeners[1].serves(ROUTES_ADMIN));
+
+        let listeners = from_config(&config(Some(&["https://0.0.0.0:8443", "http://127.0.0.1:9000?routes=metrics"]))).unwrap();
+        assert!(listeners[0].serves(ROUTES_ADMIN));
+    }
+
+    #[test]
+    fn default_listener() {
+        let listeners = from_config(&config(None)).unwrap();
+        assert_eq!(listeners.len(), 1);
+        assert_eq!(listeners[0].kind, ListenerKind::Https);
+        assert_eq!(listeners[0].route_groups, ALL_ROUTE_GROUPS.to_vec());
+
+        assert!(from_config(&config(Some(&[" "]))).is_err());
+    }
+}
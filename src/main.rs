@@ -1,73 +1,328 @@
 use tokio::sync::Semaphore;
 use std::sync::Arc;
+use std::time::Duration;
+use std::collections::{BTreeMap, HashMap};
 use actix_web::{
     middleware, 
     web, 
     App, 
     HttpServer
 };
-use yaw_dbproxy::app_config::{Config, load_openssl_config};//load_rustls_config};
+use yaw_dbproxy::app_config::{init_tracer, log_validation_report, CliArgs, Config, driver_settings, DEFAULT_CLUSTER};//load_rustls_config};
 use yaw_dbproxy::common::{ScyllaService, AppState};
-use tracing::{info};
-use yaw_dbproxy::service::{execute_statement, direct_statement, health_check};
+use yaw_dbproxy::compression;
+use yaw_dbproxy::telemetry;
+use yaw_dbproxy::request_id;
+use yaw_dbproxy::cql_classifier::GuardPolicy;
+use yaw_dbproxy::auth::{self, Authenticator, api_key::ApiKeyStore, jwt::{JwtValidator, JwtSettings}, mtls::{self, CertificateIdentities}};
+use tracing::{error, info, warn};
+use yaw_dbproxy::service::{self, admin, execute_statement, direct_statement, health_check};
+use yaw_dbproxy::metrics::{self, Metrics};
+use futures_util::future::{join_all, try_join_all};
+use yaw_dbproxy::maintenance::ReadOnlySwitch;
+use yaw_dbproxy::rate_limit::{RateLimit, RateLimiter};
+use yaw_dbproxy::redaction::Redactor;
+use yaw_dbproxy::slow_query::SlowQueryLog;
+use yaw_dbproxy::health::HealthChecker;
+use yaw_dbproxy::tls_certificate::ServerCertificate;
+use yaw_dbproxy::shutdown::Shutdown;
+use yaw_dbproxy::db_credentials::DbCredentials;
+use actix_web::dev::ServerHandle;
+use yaw_dbproxy::listener::{self, Listener, ListenerKind, ROUTES_ADMIN, ROUTES_API, ROUTES_HEALTH, ROUTES_METRICS};
+use yaw_dbproxy::audit::{Auditor, AuditSink, FileSink, TracingSink};
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
 
-    let config = Config::from_env().expect("Server configuration");
+    let cli = match CliArgs::parse(std::env::args().skip(1)) {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("^^^ COMMAND LINE ERROR: {:#}", e);
+            std::process::exit(2);
+        }
+    };
+    let config = match Config::load(&cli) {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("^^^ CONFIG ERROR: {:#}", e);
+            std::process::exit(1);
+        }
+    };
+    if cli.print_config {
+        println!("{}", serde_json::to_string_pretty(&config.redacted()).unwrap_or_default());
+        return Ok(())
+    }
+    init_tracer(&config).expect("^^^ TRACING CONFIG ERROR");
+    info!("Loading configuration");
+    if !log_validation_report(&config) {
+        error!("^^^ CONFIG ERROR: see the failed configuration checks above");
+        std::process::exit(1);
+    }
 
-    let port = config.port;
     let host = config.host.clone();
     let num_cpus = num_cpus::get();
     let parallel_files = config.parallel_files;
     let db_parallelism = config.db_parallelism;
-    let region = config.region;
+    let region = config.region.clone();
     let payload_max_size = config.payload_max_size;
+    let compression_enabled = config.compression_enabled;
+    let compression_min_size = config.compression_min_size;
 
     info!(
         "Starting application. Num CPUs {}. Max Parallel Files {}. DB Parallelism {}.  Region {}",
         num_cpus, parallel_files, db_parallelism, region
     );
 
-    let db = ScyllaService::new(
-        config.db_node0,
-        config.db_node1,
-        config.db_node2,
-        config.db_user,
-        config.db_password,
-        config.db_parallelism,
-        config.db_dc
-    ).await;
+    let listeners = listener::from_config(&config).expect("^^^ LISTENERS CONFIG ERROR");
+    let workers = listener::workers(&config).expect("^^^ WORKERS CONFIG ERROR");
+
+    // let ssl_config = load_rustls_config();
+    let certificate = if listeners.iter().any(|l| l.kind == ListenerKind::Https) {
+        let certificate = ServerCertificate::load(&config).expect("^^^ HTTPS CONFIG ERROR");
+        ServerCertificate::watch(certificate.clone(), Duration::from_secs(config.tls_reload_poll_seconds));
+        Some(certificate)
+    } else {
+        None
+    };
+    let mut bindings = vec![];
+    for listener in listeners {
+        let ssl_config = match (listener.kind, &certificate) {
+            (ListenerKind::Https, Some(certificate)) => Some(ServerCertificate::acceptor(certificate).expect("^^^ HTTPS CONFIG ERROR")),
+            _ => None
+        };
+        bindings.push((listener, ssl_config));
+    }
+
+    let mut clusters = BTreeMap::new();
+    for (cluster, cluster_config) in config.all_clusters() {
+        let driver_config = match driver_settings(cluster_config) {
+            Ok(v) => v,
+            Err(e) => {
+                error!("^^^ SCYLLA DRIVER CONFIG ERROR [{}]: {:#}", cluster, e);
+                std::process::exit(1);
+            }
+        };
+
+        DbCredentials::watch(driver_config.credentials.clone(), Duration::from_secs(cluster_config.db_credentials_poll_seconds));
+
+        clusters.insert(cluster.to_string(), Arc::new(ScyllaService::new(cluster, driver_config).await));
+    }
+    let db = clusters[DEFAULT_CLUSTER].clone();
+
+    let api_keys = config.api_keys_file.as_ref().map(|path| {
+        let store = ApiKeyStore::from_file(path).expect("^^^ API KEYS CONFIG ERROR");
+        info!("Loaded {} API keys from {}", store.len(), path);
+        store
+    });
+
+    let jwt = if config.jwt_jwks_file.is_some() || config.jwt_public_key_file.is_some() {
+        let validator = JwtValidator::new(JwtSettings {
+            jwks_file: config.jwt_jwks_file.clone(),
+            public_key_file: config.jwt_public_key_file.clone(),
+            issuer: config.jwt_issuer.clone(),
+            audience: config.jwt_audience.clone(),
+            algorithms: config.jwt_algorithms.clone(),
+            roles_file: config.jwt_roles_file.clone(),
+            leeway_seconds: config.jwt_leeway_seconds,
+        }).expect("^^^ JWT CONFIG ERROR");
+        info!("Loaded {} JWT verification keys", validator.keys_number());
+        Some(validator)
+    } else {
+        None
+    };
+
+    let certificates = config.client_certificates_file.as_ref().map(|path| {
+        let identities = CertificateIdentities::from_file(path).expect("^^^ CLIENT CERTIFICATES CONFIG ERROR");
+        info!("Loaded {} client certificate identities from {}", identities.len(), path);
+        identities
+    });
+
+    let read_only = Arc::new(ReadOnlySwitch::new(config.read_only));
+    if let Some(flag_file) = config.read_only_flag_file.clone() {
+        ReadOnlySwitch::watch_flag_file(read_only.clone(), flag_file, Duration::from_secs(config.read_only_flag_poll_seconds));
+    }
+
+    let mut statement_rate_limits = HashMap::new();
+    for (statement_id, attributes) in db.p_queries_attributes.iter() {
+        if !attributes["rate_limit"].is_null() {
+            let limit: RateLimit = serde_json::from_value(attributes["rate_limit"].clone())
+                .unwrap_or_else(|_| panic!("Statement {} has an invalid rate_limit", statement_id));
+            statement_rate_limits.insert(statement_id.clone(), limit);
+        }
+    }
+    let rate_limiter = RateLimiter::new(
+        config.rate_limit_global_per_second.map(|v| RateLimit::new(v, config.rate_limit_global_burst)),
+        config.rate_limit_identity_per_second.map(|v| RateLimit::new(v, config.rate_limit_identity_burst)),
+        statement_rate_limits
+    ).expect("^^^ RATE LIMIT CONFIG ERROR");
+
+    let mut audit_sinks: Vec<Box<dyn AuditSink>> = vec![];
+    if let Some(path) = &config.audit_file {
+        let sink = FileSink::new(path, config.audit_file_max_size_mb * 1024 * 1024, config.audit_file_max_files)
+            .expect("^^^ AUDIT FILE ERROR");
+        info!("Writing audit records to {}", path);
+        audit_sinks.push(Box::new(sink));
+    }
+    if config.audit_log {
+        audit_sinks.push(Box::new(TracingSink));
+    }
+    let auditor = Auditor::new(audit_sinks, config.audit_queue_size);
+
+    let shutdown = Arc::new(Shutdown::new());
+    Shutdown::watch_signals(shutdown.clone());
+    let grace_period = config.shutdown_grace_period_seconds;
+    let readiness_delay = config.drain_readiness_delay_seconds;
 
     let sem = Arc::new(Semaphore::new(parallel_files));
     let data = web::Data::new(AppState {
         db_svc: db,
+        clusters,
+        authenticator: Authenticator {
+            api_keys,
+            jwt,
+            certificates,
+            admin_allow_anonymous: config.admin_allow_anonymous,
+            anonymous_allow_trace: config.anonymous_allow_trace,
+        },
+        cql_guard: GuardPolicy {
+            deny_ddl: config.direct_statement_deny_ddl,
+            deny_permission_statements: config.direct_statement_deny_permission_statements,
+            deny_allow_filtering: config.direct_statement_deny_allow_filtering,
+            require_limit: config.direct_statement_require_limit,
+            allowed_keyspaces: config.direct_statement_allowed_keyspaces
+                .map(|v| v.iter().map(|k| k.trim().to_lowercase()).collect()),
+        },
+        read_only,
+        rate_limiter,
+        auditor,
+        redactor: Redactor::new(&config.log_redact_headers, &config.log_redact_fields),
+        metrics: Metrics::new().expect("^^^ METRICS ERROR"),
+        slow_queries: SlowQueryLog::new(config.slow_query_threshold_ms, config.slow_query_buffer_size),
+        health: HealthChecker::new(Duration::from_millis(config.health_cache_ms), Duration::from_millis(config.health_query_timeout_ms)),
+        certificate,
+        shutdown: shutdown.clone(),
         semaphore: sem,
         region
     });
 
-    info!("Starting server at http://{}:{}/", host, port);
+    let metrics_port = config.metrics_port;
+    let metrics_data = data.clone();
 
-    // let ssl_config = load_rustls_config();
-    let ssl_config = load_openssl_config();
+    let mut servers = vec![];
+    for (listener, ssl_config) in bindings {
+        info!("Starting server at {} serving {}", listener.url(), listener.route_groups.join(", "));
+        if !listener.routes_specified && !listener.serves(ROUTES_ADMIN) {
+            info!("Admin routes removed from {}, another listener lists them in ?routes=", listener.url());
+        }
 
-    HttpServer::new(move || {
+        let data = data.clone();
+        let served = listener.clone();
+        let server = HttpServer::new(move || {
 
-        App::new()
-            .wrap(middleware::Logger::default())
-            .app_data(web::JsonConfig::default().limit(payload_max_size))
-            .app_data(data.clone())
-            .route("/", web::get().to(health_check::index))
-            .route("/v2/", web::get().to(health_check::index))
-            .route("/v2/execute_statement", web::post().to(execute_statement::index))
-            .route("/v2/direct_statement", web::post().to(direct_statement::index))
-    })
-    .bind_openssl(format!("{}:{}", host, port), ssl_config)?
-    //.bind_rustls(format!("{}:{}", host, port), ssl_config)?
-    //.bind(format!("{}:{}", host, port))?
-    .workers(num_cpus * 2)
-    .run()
-    .await?;
+            App::new()
+                .wrap(middleware::from_fn(move |req, next| compression::negotiate(req, next, compression_enabled, compression_min_size)))
+                .wrap(middleware::from_fn(metrics::track_http))
+                .wrap(middleware::Condition::new(compression_enabled, middleware::Compress::default()))
+                .wrap(middleware::from_fn(telemetry::trace_http))
+                .wrap(middleware::from_fn(request_id::assign))
+                .wrap(middleware::Logger::new(r#"%a "%r" %s %b "%{Referer}i" "%{User-Agent}i" %T request_id=%{x-request-id}o"#))
+                .app_data(web::JsonConfig::default().limit(payload_max_size))
+                .app_data(web::PayloadConfig::new(payload_max_size))
+                .app_data(data.clone())
+                .configure(|cfg| routes(cfg, &served, metrics_port.is_none()))
+        })
+        .on_connect(mtls::on_connect);
+
+        let server = match (listener.kind, ssl_config) {
+            (ListenerKind::Https, Some(ssl_config)) => server.bind_openssl(&listener.address, ssl_config)?,
+            //.bind_rustls(format!("{}:{}", host, port), ssl_config)?
+            (ListenerKind::Unix, _) => server.bind_uds(&listener.address)?,
+            _ => server.bind(&listener.address)?
+        };
+        servers.push(server.workers(workers).disable_signals().shutdown_timeout(grace_period).run());
+    }
+
+    if let Some(metrics_port) = metrics_port {
+        info!("Serving metrics at http://{}:{}/metrics", host, metrics_port);
+        let metrics_server = HttpServer::new(move || {
+            App::new()
+                .app_data(metrics_data.clone())
+                .route("/metrics", web::get().to(service::metrics::index))
+        })
+        .bind(format!("{}:{}", host, metrics_port))?
+        .workers(1)
+        .disable_signals()
+        .run();
+        servers.push(metrics_server);
+    }
+
+    let handles: Vec<ServerHandle> = servers.iter().map(|s| s.handle()).collect();
+    actix_web::rt::spawn(async move {
+        shutdown.requested().await;
+        // Every listener, health included, stays up long enough for the load balancers to see
+        // readiness fail and stop routing new traffic here.
+        if readiness_delay > 0 {
+            info!("Readiness fails, stopping the listeners in {} s", readiness_delay);
+            actix_web::rt::time::sleep(Duration::from_secs(readiness_delay)).await;
+        }
+        info!("Stopping the listeners, in-flight requests have {} s to finish", grace_period);
+        join_all(handles.iter().map(|h| h.stop(true))).await;
+    });
+
+    try_join_all(servers).await?;
+
+    data.auditor.close();
+    info!("Audit records flushed");
+
+    match Arc::try_unwrap(data.into_inner()) {
+        Ok(state) => {
+            drop(state);
+            info!("Scylla sessions closed");
+        },
+        Err(_) => warn!("Scylla sessions still in use at shutdown")
+    };
+
+    telemetry::shutdown();
 
     Ok(())
+}
+
+/// Registers the routes of the groups served by `i_listener`.
+fn routes(cfg: &mut web::ServiceConfig, i_listener: &Listener, i_serve_metrics: bool) {
+    if i_listener.serves(ROUTES_HEALTH) {
+        cfg
+            .route("/", web::get().to(health_check::index))
+            .route("/v2/", web::get().to(health_check::index))
+            .route("/v2/health/live", web::get().to(health_check::liveness))
+            .route("/v2/health/ready", web::get().to(health_check::readiness))
+            .route("/v2/health/startup", web::get().to(health_check::startup));
+    }
+    if i_listener.serves(ROUTES_API) {
+        cfg
+            .service(
+                web::resource("/v2/execute_statement")
+                    .wrap(middleware::from_fn(auth::authenticate))
+                    .route(web::post().to(execute_statement::index))
+            )
+            .service(
+                web::resource("/v2/direct_statement")
+                    .wrap(middleware::from_fn(auth::authenticate))
+                    .route(web::post().to(direct_statement::index))
+            );
+    }
+    if i_listener.serves(ROUTES_ADMIN) {
+        cfg.service(
+            web::scope("/v2/admin")
+                .wrap(middleware::from_fn(auth::authenticate))
+                .route("/read_only", web::get().to(admin::get_read_only))
+                .route("/read_only", web::post().to(admin::set_read_only))
+                .route("/slow_queries", web::get().to(admin::get_slow_queries))
+                .route("/topology", web::get().to(admin::get_topology))
+                .route("/partition", web::post().to(admin::locate_partition))
+                .route("/drain", web::post().to(admin::drain))
+        );
+    }
+    if i_serve_metrics && i_listener.serves(ROUTES_METRICS) {
+        cfg.route("/metrics", web::get().to(service::metrics::index));
+    }
 }
\ No newline at end of file
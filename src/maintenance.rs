@@ -0,0 +1,60 @@
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// Runtime read-only switch.
+/// While it is on, writes are answered with a 503 and reads keep working.
+pub struct ReadOnlySwitch {
+    enabled: AtomicBool,
+}
+
+impl ReadOnlySwitch {
+    pub fn new(i_enabled: bool) -> ReadOnlySwitch {
+        if i_enabled {
+            warn!("Read only mode enabled at startup");
+        }
+        ReadOnlySwitch {
+            enabled: AtomicBool::new(i_enabled),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::SeqCst)
+    }
+
+    pub fn set(&self, i_enabled: bool, i_reason: &str) {
+        let previous = self.enabled.swap(i_enabled, Ordering::SeqCst);
+        if previous != i_enabled {
+            if i_enabled {
+                warn!("Read only mode enabled, reason: {}", i_reason);
+            } else {
+                info!("Read only mode disabled, reason: {}", i_reason);
+            }
+        }
+    }
+
+    /// Follows the existence of `i_flag_file`: read only while the file is there.
+    /// A file present at startup turns read only on, a missing one leaves `READ_ONLY` as it is.
+    /// After that only changes of the file are applied, so an admin override stays in place
+    /// until the file is created or removed again.
+    pub fn watch_flag_file(switch: Arc<ReadOnlySwitch>, i_flag_file: String, i_poll_interval: Duration) {
+        info!("Watching read only flag file {}", i_flag_file);
+        let mut last_seen = Path::new(&i_flag_file).exists();
+        if last_seen {
+            switch.set(true, &format!("flag file {} present at startup", i_flag_file));
+        }
+        actix_web::rt::spawn(async move {
+            let mut interval = actix_web::rt::time::interval(i_poll_interval);
+            loop {
+                interval.tick().await;
+                let exists = Path::new(&i_flag_file).exists();
+                if last_seen != exists {
+                    switch.set(exists, &format!("flag file {} {}", i_flag_file, if exists { "created" } else { "removed" }));
+                    last_seen = exists;
+                }
+            }
+        });
+    }
+}
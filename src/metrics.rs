@@ -0,0 +1,224 @@
+use actix_web::{
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    middleware::Next,
+    web,
+    Error
+};
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder
+};
+use std::time::Instant;
+use crate::common::AppState;
+
+/// Label used for direct statements, their text is never used as a label.
+pub const DIRECT_STATEMENT_LABEL: &str = "direct_statement";
+/// Label used for statement ids that are not in the catalog.
+pub const UNKNOWN_STATEMENT_LABEL: &str = "unknown";
+
+/// Prometheus metrics of the proxy.
+/// Statement labels only ever hold catalog ids, [`DIRECT_STATEMENT_LABEL`] or
+/// [`UNKNOWN_STATEMENT_LABEL`], so the series count stays bounded by the catalog size.
+pub struct Metrics {
+    registry: Registry,
+    http_requests: IntCounterVec,
+    http_latency: HistogramVec,
+    operations: IntCounterVec,
+    operation_latency: HistogramVec,
+    rows_returned: IntCounterVec,
+    errors: IntCounterVec,
+    operations_in_flight: IntGauge,
+    driver_queries: IntCounterVec,
+    driver_queries_iter: IntCounterVec,
+    driver_errors: IntCounterVec,
+    driver_errors_iter: IntCounterVec,
+    driver_retries: IntCounterVec,
+    driver_latency_avg_ms: IntGaugeVec,
+    driver_latency_p99_ms: IntGaugeVec,
+    cluster_nodes: IntGaugeVec,
+    rate_limit_checks: IntCounterVec,
+    tls_certificate_expiry: IntGauge,
+    tls_certificate_reloads: IntCounterVec,
+    db_credential_rotations: IntCounterVec,
+    audit_records_dropped: IntCounter,
+}
+
+/// Decrements the in flight gauge when the operation is over, whatever the outcome.
+pub struct InFlightGuard<'a> {
+    gauge: &'a IntGauge,
+}
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        self.gauge.dec();
+    }
+}
+
+/// Brings a counter up to a cumulative value read from elsewhere.
+fn sync_counter(i_counter: &IntCounter, i_value: u64) {
+    let current = i_counter.get();
+    if i_value > current {
+        i_counter.inc_by(i_value - current);
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Result<Metrics, prometheus::Error> {
+        let registry = Registry::new_custom(Some("dbproxy".to_string()), None)?;
+
+        let http_requests = IntCounterVec::new(Opts::new("http_requests_total", "HTTP requests by endpoint and status"), &["endpoint", "status"])?;
+        let http_latency = HistogramVec::new(HistogramOpts::new("http_request_duration_seconds", "HTTP request latency by endpoint"), &["endpoint"])?;
+        let operations = IntCounterVec::new(Opts::new("operations_total", "Operations by endpoint, statement and outcome"), &["endpoint", "statement", "outcome"])?;
+        let operation_latency = HistogramVec::new(HistogramOpts::new("operation_duration_seconds", "Operation latency by endpoint and statement"), &["endpoint", "statement"])?;
+        let rows_returned = IntCounterVec::new(Opts::new("rows_returned_total", "Rows returned by endpoint and statement"), &["endpoint", "statement"])?;
+        let errors = IntCounterVec::new(Opts::new("errors_total", "Failed operations by endpoint and status code"), &["endpoint", "code"])?;
+        let operations_in_flight = IntGauge::new("operations_in_flight", "Operations currently running")?;
+        let driver_queries = IntCounterVec::new(Opts::new("driver_queries_total", "Queries sent by the Scylla driver"), &["cluster"])?;
+        let driver_queries_iter = IntCounterVec::new(Opts::new("driver_queries_iter_total", "Paged queries sent by the Scylla driver"), &["cluster"])?;
+        let driver_errors = IntCounterVec::new(Opts::new("driver_errors_total", "Query errors seen by the Scylla driver"), &["cluster"])?;
+        let driver_errors_iter = IntCounterVec::new(Opts::new("driver_errors_iter_total", "Paged query errors seen by the Scylla driver"), &["cluster"])?;
+        let driver_retries = IntCounterVec::new(Opts::new("driver_retries_total", "Retries done by the Scylla driver"), &["cluster"])?;
+        let driver_latency_avg_ms = IntGaugeVec::new(Opts::new("driver_latency_avg_ms", "Average query latency measured by the Scylla driver"), &["cluster"])?;
+        let driver_latency_p99_ms = IntGaugeVec::new(Opts::new("driver_latency_p99_ms", "99th percentile query latency measured by the Scylla driver"), &["cluster"])?;
+        let cluster_nodes = IntGaugeVec::new(Opts::new("cluster_nodes", "Known Scylla nodes by cluster, datacenter and status"), &["cluster", "datacenter", "status"])?;
+        let rate_limit_checks = IntCounterVec::new(Opts::new("rate_limit_checks_total", "Rate limiter checks by scope, statement and outcome"), &["scope", "statement", "outcome"])?;
+        let tls_certificate_expiry = IntGauge::new("tls_certificate_expiry_timestamp_seconds", "Expiry of the HTTPS certificate in use, in seconds since the epoch")?;
+        let tls_certificate_reloads = IntCounterVec::new(Opts::new("tls_certificate_reloads_total", "HTTPS certificate reloads by outcome"), &["outcome"])?;
+        let db_credential_rotations = IntCounterVec::new(Opts::new("db_credential_rotations_total", "Scylla credentials rotations picked up from the credentials files"), &["cluster"])?;
+        let audit_records_dropped = IntCounter::new("audit_records_dropped_total", "Audit records dropped because the audit queue was full")?;
+
+        registry.register(Box::new(http_requests.clone()))?;
+        registry.register(Box::new(http_latency.clone()))?;
+        registry.register(Box::new(operations.clone()))?;
+        registry.register(Box::new(operation_latency.clone()))?;
+        registry.register(Box::new(rows_returned.clone()))?;
+        registry.register(Box::new(errors.clone()))?;
+        registry.register(Box::new(operations_in_flight.clone()))?;
+        registry.register(Box::new(driver_queries.clone()))?;
+        registry.register(Box::new(driver_queries_iter.clone()))?;
+        registry.register(Box::new(driver_errors.clone()))?;
+        registry.register(Box::new(driver_errors_iter.clone()))?;
+        registry.register(Box::new(driver_retries.clone()))?;
+        registry.register(Box::new(driver_latency_avg_ms.clone()))?;
+        registry.register(Box::new(driver_latency_p99_ms.clone()))?;
+        registry.register(Box::new(cluster_nodes.clone()))?;
+        registry.register(Box::new(rate_limit_checks.clone()))?;
+        registry.register(Box::new(tls_certificate_expiry.clone()))?;
+        registry.register(Box::new(tls_certificate_reloads.clone()))?;
+        registry.register(Box::new(db_credential_rotations.clone()))?;
+        registry.register(Box::new(audit_records_dropped.clone()))?;
+
+        Ok(Metrics {
+            registry,
+            http_requests,
+            http_latency,
+            operations,
+            operation_latency,
+            rows_returned,
+            errors,
+            operations_in_flight,
+            driver_queries,
+            driver_queries_iter,
+            driver_errors,
+            driver_errors_iter,
+            driver_retries,
+            driver_latency_avg_ms,
+            driver_latency_p99_ms,
+            cluster_nodes,
+            rate_limit_checks,
+            tls_certificate_expiry,
+            tls_certificate_reloads,
+            db_credential_rotations,
+            audit_records_dropped,
+        })
+    }
+
+    pub fn observe_http(&self, i_endpoint: &str, i_status: u16, i_started: Instant) {
+        self.http_requests.with_label_values(&[i_endpoint, &i_status.to_string()]).inc();
+        self.http_latency.with_label_values(&[i_endpoint]).observe(i_started.elapsed().as_secs_f64());
+    }
+
+    pub fn operation_started(&self) -> InFlightGuard<'_> {
+        self.operations_in_flight.inc();
+        InFlightGuard { gauge: &self.operations_in_flight }
+    }
+
+    pub fn observe_operation(
+        &self,
+        i_endpoint: &str,
+        i_statement: &str,
+        i_started: Instant,
+        i_result: &Result<serde_json::Value, serde_json::Value>
+    ) {
+        self.operation_latency.with_label_values(&[i_endpoint, i_statement]).observe(i_started.elapsed().as_secs_f64());
+        match i_result {
+            Ok(v) => {
+                self.operations.with_label_values(&[i_endpoint, i_statement, "success"]).inc();
+                self.rows_returned.with_label_values(&[i_endpoint, i_statement]).inc_by(v["records_number"].as_u64().unwrap_or(0));
+            },
+            Err(e) => {
+                self.operations.with_label_values(&[i_endpoint, i_statement, "error"]).inc();
+                self.errors.with_label_values(&[i_endpoint, &e["status_code"].as_u64().unwrap_or(500).to_string()]).inc();
+            }
+        }
+    }
+
+    /// Reads the values that are only known at scrape time, then encodes everything.
+    pub fn render(&self, state: &AppState) -> Result<String, anyhow::Error> {
+        self.cluster_nodes.reset();
+        for (name, db_svc) in state.clusters.iter() {
+            let cluster = [name.as_str()];
+            let driver = db_svc.db_session.get_metrics();
+            sync_counter(&self.driver_queries.with_label_values(&cluster), driver.get_queries_num());
+            sync_counter(&self.driver_queries_iter.with_label_values(&cluster), driver.get_queries_iter_num());
+            sync_counter(&self.driver_errors.with_label_values(&cluster), driver.get_errors_num());
+            sync_counter(&self.driver_errors_iter.with_label_values(&cluster), driver.get_errors_iter_num());
+            sync_counter(&self.driver_retries.with_label_values(&cluster), driver.get_retries_num());
+            self.driver_latency_avg_ms.with_label_values(&cluster).set(driver.get_latency_avg_ms().unwrap_or(0) as i64);
+            self.driver_latency_p99_ms.with_label_values(&cluster).set(driver.get_latency_percentile_ms(99.0).unwrap_or(0) as i64);
+
+            sync_counter(&self.db_credential_rotations.with_label_values(&cluster), db_svc.credentials.rotations());
+
+            for node in db_svc.db_session.get_cluster_data().get_nodes_info() {
+                let status = if node.is_down() { "down" } else { "up" };
+                self.cluster_nodes
+                    .with_label_values(&[name, node.datacenter.as_deref().unwrap_or("unknown"), status])
+                    .inc();
+            }
+        }
+
+        for (scope, statement, outcome, value) in state.rate_limiter.counters() {
+            sync_counter(&self.rate_limit_checks.with_label_values(&[&scope, &statement, outcome]), value);
+        }
+
+        if let Some(certificate) = &state.certificate {
+            self.tls_certificate_expiry.set(certificate.not_after());
+            let (succeeded, failed) = certificate.reloads();
+            sync_counter(&self.tls_certificate_reloads.with_label_values(&["success"]), succeeded);
+            sync_counter(&self.tls_certificate_reloads.with_label_values(&["error"]), failed);
+        }
+
+        sync_counter(&self.audit_records_dropped, state.auditor.dropped());
+
+        let mut buffer = vec![];
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(String::from_utf8(buffer)?)
+    }
+}
+
+/// Counts and times every HTTP request, labelled with the matched route pattern.
+pub async fn track_http(
+    request: ServiceRequest,
+    next: Next<impl MessageBody + 'static>
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let started = Instant::now();
+    let endpoint = request.match_pattern().unwrap_or_else(|| "unmatched".to_string());
+    let state = request.app_data::<web::Data<AppState>>().cloned();
+
+    let response = next.call(request).await?;
+
+    if let Some(state) = state {
+        state.metrics.observe_http(&endpoint, response.status().as_u16(), started);
+    }
+    Ok(response)
+}
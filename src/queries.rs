@@ -14,9 +14,16 @@ pub fn get_statements() -> serde_json::Value {
             //     "is_paged": false,
             //     "per_page_results": 0,
             //     "is_prepared": true,
+            //     "tags": ["your_tag"],
             //     "casting": {
             //         "0": "Text"
-            //     }
+            //     },
+            //     "identity_binding": {},
+            //     "sensitive": [],
+            //     "sensitive_fields": [],
+            //     "rate_limit": { "per_second": 100, "burst": 200 },
+            //     "slow_query_threshold_ms": 500,
+            //     "cluster": "default"
             // }
         }
     )
@@ -0,0 +1,324 @@
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::sync::{Mutex, MutexGuard};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+use serde_json::json;
+use anyhow::Result;
+use crate::common::erfn;
+use crate::auth::Identity;
+
+/// Idle identity buckets are dropped once the map grows past this size.
+const MAX_IDENTITY_BUCKETS: usize = 10000;
+
+/// Bucket key of the identity limit. Without authentication every caller is `anonymous`, so
+/// anonymous callers are told apart by the address of the peer instead: behind a reverse proxy
+/// that is the proxy address, and they all share one bucket.
+pub fn identity_key(identity: &Identity, i_peer: Option<SocketAddr>) -> String {
+    match (identity.method.as_str(), i_peer) {
+        ("none", Some(peer)) => format!("none:{}", peer.ip()),
+        _ => format!("{}:{}", identity.method, identity.subject)
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy)]
+pub struct RateLimit {
+    pub per_second: f64,
+    #[serde(default)]
+    pub burst: Option<f64>,
+}
+
+impl RateLimit {
+    pub fn new(i_per_second: f64, i_burst: Option<f64>) -> RateLimit {
+        RateLimit { per_second: i_per_second, burst: i_burst }
+    }
+
+    fn capacity(&self) -> f64 {
+        self.burst.unwrap_or(self.per_second).max(1.0)
+    }
+}
+
+struct TokenBucket {
+    limit: RateLimit,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+pub struct RateLimitExceeded {
+    pub scope: String,
+    pub limit: u64,
+    pub retry_after: u64,
+}
+
+impl RateLimitExceeded {
+    pub fn to_error(&self) -> serde_json::Value {
+        let mut e = erfn(
+            429,
+            format!("{} rate limit exceeded, retry in {} seconds", self.scope, self.retry_after),
+            "rate_limit_exceeded".to_string(),
+            format!("rate_limit_exceeded_{}", self.scope)
+        );
+        e["rate_limit"] = json!({
+            "scope": self.scope,
+            "limit": self.limit,
+            "remaining": 0,
+            "reset": self.retry_after
+        });
+        e
+    }
+}
+
+impl TokenBucket {
+    fn new(i_limit: RateLimit) -> TokenBucket {
+        TokenBucket {
+            limit: i_limit,
+            tokens: i_limit.capacity(),
+            last_refill: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.limit.per_second).min(self.limit.capacity());
+        self.last_refill = now;
+    }
+
+    fn is_full(&mut self) -> bool {
+        self.refill();
+        self.tokens >= self.limit.capacity()
+    }
+
+    /// Checks that `i_tokens` are there, without taking them.
+    fn reserve(&mut self, i_scope: &str, i_tokens: f64) -> Result<(), RateLimitExceeded> {
+        self.refill();
+        if self.tokens >= i_tokens {
+            return Ok(())
+        }
+        let retry_after = ((i_tokens - self.tokens) / self.limit.per_second).ceil() as u64;
+        Err(RateLimitExceeded {
+            scope: i_scope.to_string(),
+            limit: self.limit.per_second.ceil() as u64,
+            retry_after: retry_after.max(1),
+        })
+    }
+}
+
+#[derive(Default)]
+struct ScopeCounters {
+    allowed: AtomicU64,
+    rejected: AtomicU64,
+}
+
+impl ScopeCounters {
+    fn allowed(&self, i_count: u64) {
+        self.allowed.fetch_add(i_count, Ordering::Relaxed);
+    }
+
+    fn rejected(&self) {
+        self.rejected.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// In-memory token buckets, per process: one global, one per identity and one per catalog
+/// statement. A request takes one global and one identity token and one statement token per
+/// operation, all of them or none.
+pub struct RateLimiter {
+    global: Option<Mutex<TokenBucket>>,
+    per_identity: Option<RateLimit>,
+    identity_buckets: Mutex<HashMap<String, TokenBucket>>,
+    statement_buckets: HashMap<String, Mutex<TokenBucket>>,
+    global_counters: ScopeCounters,
+    identity_counters: ScopeCounters,
+    statement_counters: HashMap<String, ScopeCounters>,
+}
+
+impl RateLimiter {
+    pub fn new(
+        i_global: Option<RateLimit>,
+        i_per_identity: Option<RateLimit>,
+        i_per_statement: HashMap<String, RateLimit>
+    ) -> Result<RateLimiter, anyhow::Error> {
+        let all_limits = i_global.iter().chain(i_per_identity.iter()).chain(i_per_statement.values());
+        for limit in all_limits {
+            if limit.per_second <= 0.0 || limit.burst.map(|b| b < 1.0).unwrap_or(false) {
+                return Err(anyhow::anyhow!("rate limits need per_second > 0 and burst >= 1, found {:?}", limit));
+            }
+        }
+        Ok(RateLimiter {
+            global: i_global.map(|l| Mutex::new(TokenBucket::new(l))),
+            per_identity: i_per_identity,
+            identity_buckets: Mutex::new(HashMap::new()),
+            statement_counters: i_per_statement.keys().map(|k| (k.clone(), ScopeCounters::default())).collect(),
+            statement_buckets: i_per_statement.into_iter().map(|(k, l)| (k, Mutex::new(TokenBucket::new(l)))).collect(),
+            global_counters: ScopeCounters::default(),
+            identity_counters: ScopeCounters::default(),
+        })
+    }
+
+    /// Reserves every token the request needs before taking any, so a request refused by one
+    /// bucket doesn't drain the others. The buckets are locked global first, then identity, then
+    /// statements by id, always in the same order.
+    pub fn check(&self, i_identity: &str, i_statement_ids: &[&str]) -> Result<(), RateLimitExceeded> {
+        let mut per_statement: BTreeMap<&str, u64> = BTreeMap::new();
+        for statement_id in i_statement_ids {
+            if self.statement_buckets.contains_key(*statement_id) {
+                *per_statement.entry(*statement_id).or_default() += 1;
+            }
+        }
+
+        let mut global = self.global.as_ref().map(|b| b.lock().unwrap());
+        if let Some(bucket) = global.as_mut() {
+            if let Err(e) = bucket.reserve("global", 1.0) {
+                self.global_counters.rejected();
+                return Err(e)
+            }
+        }
+
+        let mut identity_buckets = self.identity_buckets.lock().unwrap();
+        let mut identity = match self.per_identity {
+            Some(limit) => {
+                if identity_buckets.len() >= MAX_IDENTITY_BUCKETS {
+                    identity_buckets.retain(|_, b| !b.is_full());
+                }
+                Some(identity_buckets.entry(i_identity.to_string()).or_insert_with(|| TokenBucket::new(limit)))
+            },
+            None => None
+        };
+        if let Some(bucket) = identity.as_mut() {
+            if let Err(e) = bucket.reserve("identity", 1.0) {
+                self.identity_counters.rejected();
+                return Err(e)
+            }
+        }
+
+        let mut statements: Vec<(&str, u64, MutexGuard<TokenBucket>)> = vec![];
+        for (statement_id, count) in per_statement {
+            let mut bucket = self.statement_buckets[statement_id].lock().unwrap();
+            if let Err(e) = bucket.reserve("statement", count as f64) {
+                self.statement_counters[statement_id].rejected();
+                return Err(e)
+            }
+            statements.push((statement_id, count, bucket));
+        }
+
+        if let Some(bucket) = global.as_mut() {
+            bucket.tokens -= 1.0;
+            self.global_counters.allowed(1);
+        }
+        if let Some(bucket) = identity.as_mut() {
+            bucket.tokens -= 1.0;
+            self.identity_counters.allowed(1);
+        }
+        for (statement_id, count, mut bucket) in statements {
+            bucket.tokens -= count as f64;
+            self.statement_counters[statement_id].allowed(count);
+        }
+        Ok(())
+    }
+
+    /// Allowed and rejected checks as `(scope, statement, outcome, count)`, the statement is
+    /// empty for the global and identity scopes.
+    pub fn counters(&self) -> Vec<(String, String, &'static str, u64)> {
+        let mut counters = vec![];
+        let scopes = [("global", "", &self.global_counters), ("identity", "", &self.identity_counters)];
+        let statements = self.statement_counters.iter().map(|(k, c)| ("statement", k.as_str(), c));
+        for (scope, statement, c) in scopes.into_iter().chain(statements) {
+            counters.push((scope.to_string(), statement.to_string(), "allowed", c.allowed.load(Ordering::Relaxed)));
+            counters.push((scope.to_string(), statement.to_string(), "rejected", c.rejected.load(Ordering::Relaxed)));
+        }
+        counters
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use crate::auth::Permissions;
+
+    fn identity(i_method: &str, i_subject: &str) -> Identity {
+        Identity {
+            subject: i_subject.to_string(),
+            method: i_method.to_string(),
+            permissions: Permissions::default(),
+            attributes: HashMap::new(),
+        }
+    }
+
+    fn scope(i_result: Result<(), RateLimitExceeded>) -> String {
+        i_result.map(|_| "allowed".to_string()).unwrap_or_else(|e| e.scope)
+    }
+
+    #[test]
+    fn bucket_refills_at_its_rate_up_to_the_burst() {
+        let mut bucket = TokenBucket::new(RateLimit::new(2.0, Some(4.0)));
+        assert!(bucket.reserve("global", 4.0).is_ok());
+        bucket.tokens = 0.0;
+        let e = bucket.reserve("global", 1.0).unwrap_err();
+        assert_eq!(e.retry_after, 1);
+        assert_eq!(e.limit, 2);
+        bucket.last_refill -= Duration::from_secs(1);
+        assert!(bucket.reserve("global", 2.0).is_ok());
+        assert!(bucket.reserve("global", 3.0).is_err());
+        bucket.last_refill -= Duration::from_secs(60);
+        bucket.refill();
+        assert_eq!(bucket.tokens, 4.0);
+        assert!(bucket.is_full());
+    }
+
+    #[test]
+    fn refused_request_takes_no_token() {
+        let limiter = RateLimiter::new(
+            Some(RateLimit::new(1.0, Some(10.0))),
+            Some(RateLimit::new(1.0, Some(10.0))),
+            HashMap::from([("GET_ORDER".to_string(), RateLimit::new(1.0, Some(2.0)))])
+        ).unwrap();
+        assert_eq!(scope(limiter.check("jwt:alice", &["GET_ORDER", "GET_ORDER", "GET_ORDER"])), "statement");
+        assert!(limiter.global.as_ref().unwrap().lock().unwrap().tokens > 9.9);
+        assert!(limiter.identity_buckets.lock().unwrap()["jwt:alice"].tokens > 9.9);
+        assert_eq!(scope(limiter.check("jwt:alice", &["GET_ORDER", "GET_ORDER", "OTHER"])), "allowed");
+        assert!(limiter.global.as_ref().unwrap().lock().unwrap().tokens < 9.1);
+        assert!(limiter.statement_buckets["GET_ORDER"].lock().unwrap().tokens < 0.1);
+        assert_eq!(limiter.statement_counters["GET_ORDER"].allowed.load(Ordering::Relaxed), 2);
+        assert_eq!(limiter.statement_counters["GET_ORDER"].rejected.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn identity_limit_is_per_identity() {
+        let limiter = RateLimiter::new(None, Some(RateLimit::new(0.001, Some(1.0))), HashMap::new()).unwrap();
+        assert_eq!(scope(limiter.check("jwt:alice", &[])), "allowed");
+        assert_eq!(scope(limiter.check("jwt:alice", &[])), "identity");
+        assert_eq!(scope(limiter.check("jwt:bob", &[])), "allowed");
+    }
+
+    #[test]
+    fn full_identity_buckets_are_evicted_past_the_maximum() {
+        let limit = RateLimit::new(0.001, Some(1.0));
+        let limiter = RateLimiter::new(None, Some(limit), HashMap::new()).unwrap();
+        {
+            let mut buckets = limiter.identity_buckets.lock().unwrap();
+            for idx in 0..MAX_IDENTITY_BUCKETS {
+                let mut bucket = TokenBucket::new(limit);
+                if idx % 2 == 0 {
+                    bucket.tokens = 0.0;
+                }
+                buckets.insert(format!("jwt:user{}", idx), bucket);
+            }
+        }
+        assert_eq!(scope(limiter.check("jwt:newcomer", &[])), "allowed");
+        let buckets = limiter.identity_buckets.lock().unwrap();
+        assert_eq!(buckets.len(), MAX_IDENTITY_BUCKETS / 2 + 1);
+        assert!(buckets.contains_key("jwt:user0"));
+        assert!(!buckets.contains_key("jwt:user1"));
+    }
+
+    #[test]
+    fn anonymous_callers_are_keyed_by_address() {
+        let peer: SocketAddr = "10.0.0.7:52000".parse().unwrap();
+        assert_eq!(identity_key(&identity("none", "anonymous"), Some(peer)), "none:10.0.0.7");
+        assert_eq!(identity_key(&identity("none", "anonymous"), None), "none:anonymous");
+        assert_eq!(identity_key(&identity("jwt", "alice"), Some(peer)), "jwt:alice");
+    }
+}
@@ -0,0 +1,185 @@
+use std::collections::HashMap;
+use serde_json::json;
+use crate::audit::{redact_parameters, REDACTED};
+
+/// Masks secrets and personal data before anything reaches the logs.
+/// Headers are masked by name, request parameters by the catalog `sensitive` positions and
+/// result fields by the catalog `sensitive_fields` plus the globally configured field names.
+#[derive(Debug, Clone, Default)]
+pub struct Redactor {
+    headers: Vec<String>,
+    fields: Vec<String>,
+}
+
+fn mask_fields(i_value: &serde_json::Value, i_fields: &[String]) -> serde_json::Value {
+    match i_value {
+        serde_json::Value::Object(map) => serde_json::Value::Object(
+            map.iter()
+                .map(|(k, v)| {
+                    if i_fields.iter().any(|f| f == k) {
+                        (k.clone(), json!(REDACTED))
+                    } else {
+                        (k.clone(), mask_fields(v, i_fields))
+                    }
+                })
+                .collect()
+        ),
+        serde_json::Value::Array(values) => serde_json::Value::Array(
+            values.iter().map(|v| mask_fields(v, i_fields)).collect()
+        ),
+        other => other.clone()
+    }
+}
+
+fn string_list(i_value: Option<&serde_json::Value>) -> Vec<String> {
+    i_value
+        .and_then(|v| v.as_array())
+        .map(|v| v.iter().filter_map(|s| s.as_str().map(|s| s.to_string())).collect())
+        .unwrap_or_default()
+}
+
+impl Redactor {
+    pub fn new(i_headers: &[String], i_fields: &[String]) -> Redactor {
+        Redactor {
+            headers: i_headers.iter().map(|h| h.trim().to_lowercase()).collect(),
+            fields: i_fields.iter().map(|f| f.trim().to_string()).collect(),
+        }
+    }
+
+    pub fn is_sensitive_header(&self, i_name: &str) -> bool {
+        self.headers.iter().any(|h| h.eq_ignore_ascii_case(i_name))
+    }
+
+    /// Copy of the `init_input` output that is safe to log: listed headers are masked and so
+    /// are the sensitive `query_data` positions of catalog statements.
+    pub fn redact_request(
+        &self,
+        i_request: &serde_json::Value,
+        i_attributes: &HashMap<String, HashMap<String, serde_json::Value>>
+    ) -> serde_json::Value {
+        let mut redacted = i_request.clone();
+
+        if let Some(headers) = redacted["headers"].as_array_mut() {
+            for header in headers.iter_mut() {
+                if let Some(map) = header.as_object_mut() {
+                    for (name, value) in map.iter_mut() {
+                        if self.is_sensitive_header(name) {
+                            *value = json!(REDACTED);
+                        }
+                    }
+                }
+            }
+        }
+
+        if let Some(operations) = redacted["body"]["operation"].as_array_mut() {
+            for operation in operations.iter_mut() {
+                let statement_id = operation["statement_id"].as_str().unwrap_or("").to_string();
+                if let Some(query_data) = operation["query_data"].as_array() {
+                    operation["query_data"] = json!(redact_parameters(i_attributes.get(&statement_id), query_data));
+                }
+                if operation.get("statement").is_some() {
+                    operation["statement"] = json!(REDACTED);
+                }
+            }
+        }
+
+        redacted
+    }
+
+    /// Copy of a response, keyed by statement id, with the sensitive result fields masked.
+    pub fn redact_response(
+        &self,
+        i_response: &serde_json::Value,
+        i_attributes: &HashMap<String, HashMap<String, serde_json::Value>>
+    ) -> serde_json::Value {
+        let map = match i_response.as_object() {
+            Some(v) => v,
+            None => return mask_fields(i_response, &self.fields)
+        };
+        serde_json::Value::Object(
+            map.iter()
+                .map(|(statement_id, result)| {
+                    let mut fields = self.fields.clone();
+                    fields.extend(string_list(i_attributes.get(statement_id).and_then(|a| a.get("sensitive_fields"))));
+                    (statement_id.clone(), mask_fields(result, &fields))
+                })
+                .collect()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn catalog() -> HashMap<String, HashMap<String, serde_json::Value>> {
+        HashMap::from([(
+            "GET_CUSTOMER".to_string(),
+            HashMap::from([
+                ("sensitive".to_string(), json!(["1"])),
+                ("sensitive_fields".to_string(), json!(["email"])),
+            ])
+        )])
+    }
+
+    fn redactor() -> Redactor {
+        Redactor::new(&[" Authorization ".to_string(), "X-Api-Key".to_string()], &["password".to_string()])
+    }
+
+    #[test]
+    fn listed_headers_are_masked_whatever_their_case() {
+        let request = json!({
+            "headers": [
+                {"authorization": "Bearer eyJhbGciOi"},
+                {"x-api-key": "k-123"},
+                {"content-type": "application/json"}
+            ],
+            "body": {}
+        });
+        let redacted = redactor().redact_request(&request, &catalog());
+        assert_eq!(redacted["headers"][0]["authorization"], json!(REDACTED));
+        assert_eq!(redacted["headers"][1]["x-api-key"], json!(REDACTED));
+        assert_eq!(redacted["headers"][2]["content-type"], json!("application/json"));
+        assert!(redactor().is_sensitive_header("X-API-KEY"));
+    }
+
+    #[test]
+    fn sensitive_positions_and_direct_statements_are_masked() {
+        let request = json!({
+            "headers": [],
+            "body": {
+                "operation": [
+                    {"statement_id": "GET_CUSTOMER", "query_data": ["acme", "alice@example.com"]},
+                    {"statement_id": "NOT_IN_CATALOG", "query_data": ["x"]},
+                    {"statement_id": "adhoc", "statement": "SELECT * FROM shop.customers WHERE email = 'alice@example.com'"}
+                ]
+            }
+        });
+        let redacted = redactor().redact_request(&request, &catalog());
+        let operations = &redacted["body"]["operation"];
+        assert_eq!(operations[0]["query_data"], json!(["acme", REDACTED]));
+        assert_eq!(operations[1]["query_data"], json!([REDACTED]));
+        assert_eq!(operations[2]["statement"], json!(REDACTED));
+        assert_eq!(request["body"]["operation"][0]["query_data"][1], json!("alice@example.com"));
+    }
+
+    #[test]
+    fn sensitive_fields_are_masked_at_any_depth() {
+        let response = json!({
+            "GET_CUSTOMER": {
+                "records": [
+                    {"name": "Alice", "email": "alice@example.com", "account": {"password": "hunter2", "plan": "pro"}}
+                ]
+            },
+            "OTHER": {"records": [{"email": "bob@example.com", "password": "x"}]}
+        });
+        let redacted = redactor().redact_response(&response, &catalog());
+        let alice = &redacted["GET_CUSTOMER"]["records"][0];
+        assert_eq!(alice["name"], json!("Alice"));
+        assert_eq!(alice["email"], json!(REDACTED));
+        assert_eq!(alice["account"]["password"], json!(REDACTED));
+        assert_eq!(alice["account"]["plan"], json!("pro"));
+        assert_eq!(redacted["OTHER"]["records"][0]["email"], json!("bob@example.com"));
+        assert_eq!(redacted["OTHER"]["records"][0]["password"], json!(REDACTED));
+    }
+}
@@ -0,0 +1,68 @@
+use actix_web::{
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    http::header::{HeaderName, HeaderValue},
+    middleware::Next,
+    Error,
+    HttpMessage,
+    HttpRequest
+};
+use rand::RngCore;
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+
+/// Correlation id of the request, stored in the request extensions by `assign`.
+#[derive(Debug, Clone)]
+pub struct RequestId(pub String);
+
+impl RequestId {
+    /// Empty when the request did not go through `assign`.
+    pub fn from_request(request: &HttpRequest) -> String {
+        request
+            .extensions()
+            .get::<RequestId>()
+            .map(|r| r.0.clone())
+            .unwrap_or_default()
+    }
+}
+
+/// Client ids are kept when they are short printable tokens, so they can't break log lines.
+fn is_acceptable(value: &str) -> bool {
+    !value.is_empty()
+        && value.len() <= MAX_REQUEST_ID_LENGTH
+        && value.chars().all(|c| c.is_ascii_alphanumeric() || "-_.:".contains(c))
+}
+
+/// Random UUID v4.
+fn generate() -> String {
+    let mut bytes = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    format!("{}-{}-{}-{}-{}", &hex[0..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..32])
+}
+
+/// Takes the `X-Request-Id` of the client or generates one, and echoes it in the response.
+pub async fn assign(
+    request: ServiceRequest,
+    next: Next<impl MessageBody>
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+
+    let request_id = match request.headers().get(REQUEST_ID_HEADER).and_then(|v| v.to_str().ok()) {
+        Some(v) if is_acceptable(v) => v.to_string(),
+        _ => generate()
+    };
+    request.extensions_mut().insert(RequestId(request_id.clone()));
+
+    let mut response = next.call(request).await?;
+
+    if let Ok(value) = HeaderValue::from_str(&request_id) {
+        response
+            .headers_mut()
+            .insert(HeaderName::from_static(REQUEST_ID_HEADER), value);
+    }
+
+    Ok(response)
+}
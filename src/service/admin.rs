@@ -0,0 +1,148 @@
+use actix_web::{
+    web, 
+    Error, 
+    HttpRequest, 
+    HttpResponse
+};
+use serde_json::json;
+use crate::common::{error_response, init_input, AppState, erfn};
+use crate::auth::Identity;
+use crate::topology;
+use crate::app_config::DEFAULT_CLUSTER;
+use anyhow::Result;
+use serde::Deserialize;
+
+#[derive(Deserialize)]
+pub struct SlowQueriesParams {
+    limit: Option<usize>,
+}
+
+#[derive(Deserialize)]
+pub struct TopologyParams {
+    cluster: Option<String>,
+}
+
+/// Without an authentication method the caller is anonymous and gets a 401, unless
+/// `ADMIN_ALLOW_ANONYMOUS` opens the admin endpoints.
+pub fn check_admin(identity: &Identity) -> Result<(), serde_json::Value> {
+    match (identity.permissions.admin, identity.method.as_str()) {
+        (true, _) => Ok(()),
+        (false, "none") => Err(
+            erfn(401, "the admin endpoints need an authenticated identity".to_string(), "admin_authentication_required".to_string(), "admin_authentication_required".to_string())
+        ),
+        (false, _) => Err(
+            erfn(403, format!("{} is not an admin", identity.subject), "admin_required".to_string(), "admin_required".to_string())
+        )
+    }
+}
+
+fn read_only_status(state: &AppState) -> serde_json::Value {
+    json!({
+        "read_only": state.read_only.is_enabled()
+    })
+}
+
+pub async fn get_read_only(request: HttpRequest, state: web::Data<AppState>) -> Result<HttpResponse, Error> {
+    if let Err(e) = check_admin(&Identity::from_request(&request)) {
+        return Ok(error_response(&e))
+    };
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .body(read_only_status(&state).to_string()))
+}
+
+fn set_read_only_logic(request: HttpRequest, body: web::Bytes, state: &AppState) -> Result<serde_json::Value, serde_json::Value> {
+    let identity = Identity::from_request(&request);
+    check_admin(&identity)?;
+
+    let oj = init_input(request, body)?;
+    let enabled = match oj["body"]["read_only"].as_bool() {
+        Some(v) => v,
+        None => return Err(
+            erfn(400, "read_only_must_be_a_boolean".to_string(), "read_only_must_be_a_boolean".to_string(), "read_only_must_be_a_boolean".to_string())
+        )
+    };
+    let reason = oj["body"]["reason"].as_str().unwrap_or("no reason given");
+
+    state.read_only.set(enabled, &format!("set by {}: {}", identity.subject, reason));
+
+    Ok(read_only_status(state))
+}
+
+pub async fn set_read_only(request: HttpRequest, body: web::Bytes, state: web::Data<AppState>) -> Result<HttpResponse, Error> {
+    let oj = match set_read_only_logic(request, body, &state) {
+        Ok(v) => v,
+        Err(e) => return Ok(error_response(&e))
+    };
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .body(oj.to_string()))
+}
+
+pub async fn get_slow_queries(request: HttpRequest, params: web::Query<SlowQueriesParams>, state: web::Data<AppState>) -> Result<HttpResponse, Error> {
+    if let Err(e) = check_admin(&Identity::from_request(&request)) {
+        return Ok(error_response(&e))
+    };
+    let entries = state.slow_queries.recent(params.limit.unwrap_or(usize::MAX));
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .body(json!({ "slow_queries": entries }).to_string()))
+}
+
+pub async fn get_topology(request: HttpRequest, params: web::Query<TopologyParams>, state: web::Data<AppState>) -> Result<HttpResponse, Error> {
+    if let Err(e) = check_admin(&Identity::from_request(&request)) {
+        return Ok(error_response(&e))
+    };
+    let db_svc = match state.cluster(params.cluster.as_deref().unwrap_or(DEFAULT_CLUSTER)) {
+        Ok(v) => v,
+        Err(e) => return Ok(error_response(&e))
+    };
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .body(topology::describe(db_svc).await.to_string()))
+}
+
+fn locate_partition_logic(request: HttpRequest, body: web::Bytes, state: &AppState) -> Result<serde_json::Value, serde_json::Value> {
+    check_admin(&Identity::from_request(&request))?;
+
+    let oj = init_input(request, body)?;
+    let (keyspace, table, partition_key) = match (oj["body"]["keyspace"].as_str(), oj["body"]["table"].as_str(), oj["body"]["partition_key"].as_array()) {
+        (Some(k), Some(t), Some(p)) => (k, t, p),
+        _ => return Err(
+            erfn(400, "keyspace_table_and_partition_key_are_required".to_string(), "keyspace_table_and_partition_key_are_required".to_string(), "keyspace_table_and_partition_key_are_required".to_string())
+        )
+    };
+
+    let db_svc = match &oj["body"]["cluster"] {
+        serde_json::Value::Null => state.cluster(DEFAULT_CLUSTER)?,
+        serde_json::Value::String(cluster) => state.cluster(cluster)?,
+        _ => return Err(
+            erfn(400, "cluster_must_be_a_string".to_string(), "cluster_must_be_a_string".to_string(), "cluster_must_be_a_string".to_string())
+        )
+    };
+
+    topology::locate_partition(db_svc, keyspace, table, partition_key)
+}
+
+pub async fn locate_partition(request: HttpRequest, body: web::Bytes, state: web::Data<AppState>) -> Result<HttpResponse, Error> {
+    let oj = match locate_partition_logic(request, body, &state) {
+        Ok(v) => v,
+        Err(e) => return Ok(error_response(&e))
+    };
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .body(oj.to_string()))
+}
+
+/// Same sequence as SIGTERM: readiness fails, the listeners stop and the process exits once
+/// in-flight requests are done.
+pub async fn drain(request: HttpRequest, state: web::Data<AppState>) -> Result<HttpResponse, Error> {
+    let identity = Identity::from_request(&request);
+    if let Err(e) = check_admin(&identity) {
+        return Ok(error_response(&e))
+    };
+    state.shutdown.request(&format!("drain requested by {}", identity.subject));
+    Ok(HttpResponse::Accepted()
+        .content_type("application/json")
+        .body(json!({ "draining": true }).to_string()))
+}
@@ -2,19 +2,33 @@ use actix_web::{
     web, 
     Error, 
     HttpRequest, 
-    HttpResponse,
-    http::{header::ContentType, StatusCode}
+    HttpResponse
 };
 use serde_json::json;
-use crate::common::{decode_status_code, init_input, AppState, erfn};
+use crate::common::{attach_tracing, error_response, draining_error, init_input, read_only_error, ExecutionInfo, sha256_hex, AppState, erfn};
+use crate::auth::Identity;
+use crate::request_id::RequestId;
+use crate::rate_limit;
+use crate::audit::AuditRecord;
+use std::collections::HashMap;
+use tracing::{debug, field, info_span, warn, Instrument};
+use crate::telemetry;
+use crate::slow_query::SlowQuery;
+use std::time::Instant;
+use crate::metrics::DIRECT_STATEMENT_LABEL;
+use crate::cql_classifier::classify;
+use crate::app_config::DEFAULT_CLUSTER;
 use anyhow::Result;
 
-async fn execute_single_statement(i_statement: String, i_paging: String, i_per_page_results: u64, state: &AppState) -> Result<serde_json::Value, serde_json::Value> {
+async fn execute_single_statement(i_cluster: &str, i_statement: String, i_paging: String, i_per_page_results: u64, trace: bool, state: &AppState) -> Result<(serde_json::Value, ExecutionInfo), serde_json::Value> {
 
-    let rj = match state.db_svc.direct_statement(
+    let db_svc = state.cluster(i_cluster)?;
+
+    let rj = match db_svc.direct_statement(
         i_statement,
         i_per_page_results,
-        i_paging
+        i_paging,
+        trace
     ).await {
         Ok(v) => v,
         Err(e) => return Err(
@@ -26,8 +40,80 @@ async fn execute_single_statement(i_statement: String, i_paging: String, i_per_p
 
 }
 
+fn check_permissions(i_statement: &str, identity: &Identity, state: &AppState) -> Result<(), serde_json::Value> {
+    if !identity.permissions.allow_direct_statement {
+        return Err(
+            erfn(403, format!("direct statements not allowed for {}", identity.subject), "direct_statement_not_allowed".to_string(), "direct_statement_not_allowed".to_string())
+        )
+    };
+    let classified = match classify(i_statement) {
+        Ok(v) => v,
+        Err(e) => return Err(
+            erfn(400, e.message, "statement_not_parsable".to_string(), "statement_not_parsable".to_string())
+        )
+    };
+    if identity.permissions.read_only && !classified.is_read() {
+        return Err(
+            erfn(403, format!("{} is read only", identity.subject), "identity_is_read_only".to_string(), "identity_is_read_only".to_string())
+        )
+    };
+    if let Err(v) = state.cql_guard.check(&classified) {
+        return Err(
+            erfn(403, v.message, "guardrail_violation".to_string(), v.rule)
+        )
+    };
+    Ok(())
+}
+
+fn authorize_operation(lline_d: &serde_json::Value, identity: &Identity, state: &AppState) -> Result<(), serde_json::Value> {
+    check_permissions(lline_d["statement"].as_str().unwrap(), identity, state)?;
+    if lline_d["trace"].as_bool().unwrap_or(false) && !identity.permissions.allow_trace {
+        return Err(
+            erfn(403, format!("tracing not allowed for {}", identity.subject), "trace_not_allowed".to_string(), "trace_not_allowed".to_string())
+        )
+    };
+    Ok(())
+}
+
+/// Audit record and metrics of an operation refused before the request ran, returns the error.
+fn reject_operation(lline_d: &serde_json::Value, e: serde_json::Value, identity: &Identity, request_id: &str, state: &AppState) -> serde_json::Value {
+    let started = Instant::now();
+    let result = Err(e.clone());
+    state.auditor.record(AuditRecord::new(
+        identity,
+        "direct_statement",
+        lline_d["statement_id"].as_str().unwrap(),
+        Some(sha256_hex(lline_d["statement"].as_str().unwrap())),
+        vec![],
+        started,
+        &result
+    ).with_request_id(request_id));
+    state.metrics.observe_operation("direct_statement", DIRECT_STATEMENT_LABEL, started, &result);
+    e
+}
+
+async fn execute_operation(lline_d: &serde_json::Value, state: &AppState) -> Result<(serde_json::Value, ExecutionInfo), serde_json::Value> {
+    let trace = lline_d["trace"].as_bool().unwrap_or(false);
+    execute_single_statement(
+        lline_d["cluster"].as_str().unwrap_or(DEFAULT_CLUSTER),
+        lline_d["statement"].as_str().unwrap().to_string(),
+        lline_d["paging"].as_str().unwrap().to_string(),
+        lline_d["per_page_results"].as_u64().unwrap(),
+        trace,
+        state
+    ).await
+}
+
 async fn main_logic(request: HttpRequest, body: web::Bytes, state: &AppState) -> Result<serde_json::Value, serde_json::Value> {
 
+    if state.shutdown.is_draining() {
+        return Err(draining_error())
+    };
+
+    let identity = Identity::from_request(&request);
+    let request_id = RequestId::from_request(&request);
+    let rate_limit_key = rate_limit::identity_key(&identity, request.peer_addr());
+
     let oj: serde_json::Value = match init_input(request, body) {
         Ok(v) => match check_input_data(v) {
             Ok(w) => w,
@@ -36,19 +122,78 @@ async fn main_logic(request: HttpRequest, body: web::Bytes, state: &AppState) ->
         Err(e) => return Err(e)
     };
 
-    let mut rj = json!({});
-
+    // Every operation is checked before the first one runs, a request refused on its second
+    // operation must not have applied the first one.
+    let mut operations = vec![];
     for lline in oj["body"]["operation"].as_array().unwrap() {
         let lline_d = match check_input_data_single_line(lline) {
             Ok(v) => v,
             Err(e) => return Err(e)
         };
-        rj[lline_d["statement_id"].as_str().unwrap().to_string()] = match execute_single_statement(
-            lline_d["statement"].as_str().unwrap().to_string(),
-            lline_d["paging"].as_str().unwrap().to_string(),
-            lline_d["per_page_results"].as_u64().unwrap(),
-            state
-        ).await {
+        if let Err(e) = authorize_operation(lline_d, &identity, state) {
+            return Err(reject_operation(lline_d, e, &identity, &request_id, state))
+        };
+        operations.push(lline_d);
+    }
+
+    // Read only mode refuses the whole request when one of its operations writes.
+    if state.read_only.is_enabled() {
+        let first_write = operations.iter().find(|lline_d| {
+            classify(lline_d["statement"].as_str().unwrap()).map(|c| !c.is_read()).unwrap_or(true)
+        });
+        if let Some(lline_d) = first_write {
+            return Err(reject_operation(lline_d, read_only_error(), &identity, &request_id, state))
+        };
+    };
+
+    if let Err(e) = state.rate_limiter.check(&rate_limit_key, &[]) {
+        return Err(e.to_error())
+    };
+
+    let mut rj = json!({});
+
+    for lline_d in operations {
+        let statement = lline_d["statement"].as_str().unwrap();
+        let started = Instant::now();
+        let in_flight = state.metrics.operation_started();
+        let span = info_span!(
+            "operation",
+            endpoint = "direct_statement",
+            statement_id = %lline_d["statement_id"].as_str().unwrap(),
+            otel.status_code = field::Empty,
+            error.type = field::Empty
+        );
+        let outcome = execute_operation(lline_d, state).instrument(span.clone()).await;
+        let latency = started.elapsed();
+        telemetry::record_result(&span, &outcome);
+        drop(in_flight);
+        let statement_hash = sha256_hex(statement);
+        if let Some(threshold) = state.slow_queries.exceeded(None, latency) {
+            state.slow_queries.record(SlowQuery::new(
+                &request_id,
+                "direct_statement",
+                lline_d["statement_id"].as_str().unwrap(),
+                statement_hash[..16].to_string(),
+                latency,
+                threshold,
+                &outcome
+            ));
+        }
+        let result = match outcome {
+            Ok((v, execution)) => Ok(attach_tracing(v, &execution, lline_d["trace_events"].as_bool().unwrap_or(false), state.cluster(lline_d["cluster"].as_str().unwrap_or(DEFAULT_CLUSTER)).unwrap_or(&state.db_svc)).await),
+            Err(e) => Err(e)
+        };
+        state.auditor.record(AuditRecord::new(
+            &identity,
+            "direct_statement",
+            lline_d["statement_id"].as_str().unwrap(),
+            Some(statement_hash),
+            vec![],
+            started,
+            &result
+        ).with_request_id(&request_id));
+        state.metrics.observe_operation("direct_statement", DIRECT_STATEMENT_LABEL, started, &result);
+        rj[lline_d["statement_id"].as_str().unwrap().to_string()] = match result {
             Ok(v) => v,
             Err(e) => return Err(e)
         };
@@ -112,24 +257,36 @@ pub fn check_input_data_single_line(oj: &serde_json::Value) -> Result<&serde_jso
             erfn(400, "paging_must_be_a_string".to_string(), "paging_must_be_a_string".to_string(), "paging_must_be_a_string".to_string())
         )
     };
+    if !oj["trace"].is_null() && !oj["trace"].is_boolean() {
+        return Err(
+            erfn(400, "trace_must_be_a_boolean".to_string(), "trace_must_be_a_boolean".to_string(), "trace_must_be_a_boolean".to_string())
+        )
+    };
+    if !oj["trace_events"].is_null() && !oj["trace_events"].is_boolean() {
+        return Err(
+            erfn(400, "trace_events_must_be_a_boolean".to_string(), "trace_events_must_be_a_boolean".to_string(), "trace_events_must_be_a_boolean".to_string())
+        )
+    };
+    if !oj["cluster"].is_null() && !oj["cluster"].is_string() {
+        return Err(
+            erfn(400, "cluster_must_be_a_string".to_string(), "cluster_must_be_a_string".to_string(), "cluster_must_be_a_string".to_string())
+        )
+    };
     Ok(oj)
 }
 
 pub async fn index(request: HttpRequest, body: web::Bytes, state: web::Data<AppState>) -> Result<HttpResponse, Error> {
     let state: &AppState = &state;
+    let request_id = RequestId::from_request(&request);
     let oj: serde_json::Value = match main_logic(request, body, state).await {
         Ok(v) => v,
-        Err(e) => {
-            let ret_status_code: u16 = match decode_status_code(&e["status_code"]) {
-                Ok(n) => n,
-                Err(_e) => 500
-            };
-            println!("ERRORE - RESPONSE {}", e.to_string());
-            return Ok(HttpResponse::build(StatusCode::from_u16(ret_status_code).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR))
-            .insert_header(ContentType::json())
-            .body(e.to_string()))
+        Err(mut e) => {
+            warn!("Error response {}", e);
+            e["request_id"] = json!(request_id);
+            return Ok(error_response(&e))
         }
     };
+    debug!(response = %state.redactor.redact_response(&oj, &HashMap::new()), "Response sent");
     Ok(HttpResponse::Ok()
         .content_type("application/json")
         .body(oj.to_string()))
@@ -2,23 +2,118 @@ use actix_web::{
     web, 
     Error, 
     HttpRequest, 
-    HttpResponse,
-    http::{header::ContentType, StatusCode}
+    HttpResponse
 };
 use serde_json::json;
-use crate::common::{decode_status_code, init_input, AppState, erfn};
+use crate::common::{attach_tracing, error_response, draining_error, init_input, read_only_error, ExecutionInfo, AppState, erfn};
+use crate::auth::Identity;
+use crate::request_id::RequestId;
+use crate::rate_limit;
+use crate::audit::{redact_parameters, AuditRecord, REDACTED};
+use tracing::{debug, field, info_span, warn, Instrument};
+use crate::telemetry;
+use crate::slow_query::{self, SlowQuery};
+use std::time::Instant;
+use crate::metrics::UNKNOWN_STATEMENT_LABEL;
 use anyhow::Result;
 use scylla::frame::value::Value;
 use std::str::FromStr;
+use std::collections::HashMap;
 use scylla::frame::response::result::CqlValue;
 
-async fn execute_single_statement(i_statement: String, i_paging: String, i_query_data: &Vec<serde_json::Value>, state: &AppState) -> Result<serde_json::Value, serde_json::Value> {
+/// Whether a JSON value has the type the casting converts, the ones not listed accept any value
+/// and report their own errors.
+fn fits_casting(i_value: &serde_json::Value, i_casting: &str) -> bool {
+    match i_casting {
+        "Text" => i_value.is_string(),
+        "Boolean" => i_value.is_boolean(),
+        "Double" | "Float" => i_value.is_f64(),
+        "Int" | "BigInt" | "SmallInt" | "TinyInt" => i_value.is_i64(),
+        "Empty" => i_value.is_null(),
+        // checked by their conversion
+        "Decimal" | "Map" => true,
+        _ => false
+    }
+}
+
+/// Merges the client values with the positions the catalog fills from the caller's identity.
+/// The client only sends the positions that are not bound, so it can never override them.
+/// A client value of the wrong type is a 400, an identity attribute of the wrong type means the
+/// catalog binds a claim or certificate attribute that doesn't fit the column, a 500.
+/// The statement has one position per `casting` entry, the client must send exactly the ones
+/// that are not bound.
+fn bind_query_data(i_statement: &str, attributes: &HashMap<String, serde_json::Value>, i_query_data: &[serde_json::Value], identity: &Identity) -> Result<Vec<serde_json::Value>, serde_json::Value> {
+
+    let empty_bindings = serde_json::Map::new();
+    let bindings = attributes["identity_binding"].as_object().unwrap_or(&empty_bindings);
+    let positions = attributes["casting"].as_object().map(|c| c.len()).unwrap_or(0);
+
+    let mut client_values = i_query_data.iter();
+    let mut query_data = vec![];
+
+    for idx in 0..positions {
+        let casting = match attributes["casting"][&idx.to_string()].as_str() {
+            Some(v) => v,
+            None => return Err(
+                erfn(500, format!("statement {} has no casting for position {}", i_statement, idx), "casting_missing".to_string(), "casting_missing".to_string())
+            )
+        };
+        let value = match bindings.get(&idx.to_string()) {
+            Some(source) => {
+                let source = source.as_str().unwrap_or("");
+                match identity.attributes.get(source) {
+                    Some(v) if fits_casting(v, casting) => v.clone(),
+                    Some(_) => return Err(
+                        erfn(500, format!("statement {} binds {} to position {}, which is not a {}", i_statement, source, idx, casting), "identity_attribute_type_mismatch".to_string(), "identity_attribute_type_mismatch".to_string())
+                    ),
+                    None => return Err(
+                        erfn(403, format!("statement {} needs {} from the caller identity", i_statement, source), "identity_attribute_missing".to_string(), "identity_attribute_missing".to_string())
+                    )
+                }
+            },
+            None => match client_values.next() {
+                Some(v) if fits_casting(v, casting) => v.clone(),
+                Some(_) => return Err(
+                    erfn(400, format!("statement {} expects a {} at position {}", i_statement, casting, idx), "value_type_mismatch".to_string(), "value_type_mismatch".to_string())
+                ),
+                None => return Err(
+                    erfn(400, format!("statement {} misses the value for position {}", i_statement, idx), "query_data_too_short".to_string(), "query_data_too_short".to_string())
+                )
+            }
+        };
+        query_data.push(value);
+    }
+
+    if client_values.next().is_some() {
+        return Err(
+            erfn(400, format!("statement {} takes {} values", i_statement, positions.saturating_sub(bindings.len())), "query_data_too_long".to_string(), "query_data_too_long".to_string())
+        )
+    };
+
+    Ok(query_data)
+}
+
+async fn execute_single_statement(i_statement: String, i_paging: String, i_query_data: &[serde_json::Value], trace: bool, identity: &Identity, state: &AppState) -> Result<(serde_json::Value, ExecutionInfo), serde_json::Value> {
+
+    let bound_query_data = bind_query_data(&i_statement, &state.db_svc.p_queries_attributes[&i_statement], i_query_data, identity)?;
 
     let mut query_data: Vec<Box<dyn Value>> = vec![];
 
-    for (idx, lline) in i_query_data.iter().enumerate() {
-        let scylla_value_type: String = state.db_svc.p_queries_attributes[&i_statement]["casting"][&idx.to_string()].as_str().unwrap().to_string();
-        let logging_str = format!("statement: {} - value: {} - casting: {}", i_statement, lline.to_string(), scylla_value_type);
+    let sensitive_positions = state.db_svc.p_queries_attributes[&i_statement]["sensitive"].clone();
+
+    for (idx, lline) in bound_query_data.iter().enumerate() {
+        let scylla_value_type: String = match state.db_svc.p_queries_attributes[&i_statement]["casting"][&idx.to_string()].as_str() {
+            Some(v) => v.to_string(),
+            None => return Err(
+                erfn(500, format!("statement {} has no casting for position {}", i_statement, idx), "casting_missing".to_string(), "casting_missing".to_string())
+            )
+        };
+        let is_sensitive = sensitive_positions
+            .as_array()
+            .map(|s| s.iter().any(|p| p.as_str() == Some(&idx.to_string())))
+            .unwrap_or(false);
+        let logged_value = if is_sensitive { json!(REDACTED) } else { lline.clone() };
+        let logging_str = format!("statement: {} - position: {} - value: {} - casting: {}", i_statement, idx, logged_value, scylla_value_type);
         let boxed_value_to_push: Box<dyn Value> = match cast_json_value_to_scylla_value(
             lline, 
             scylla_value_type,
@@ -30,10 +125,18 @@ async fn execute_single_statement(i_statement: String, i_paging: String, i_query
         query_data.push(boxed_value_to_push);
     };
 
-    let rj = match state.db_svc.cql_statement(
+    let db_svc = state.statement_cluster(&i_statement)?;
+    if let Some(e) = db_svc.prepare_errors.get(&i_statement) {
+        return Err(
+            erfn(503, format!("statement {} could not be prepared: {}", i_statement, e), "statement_not_prepared".to_string(), "statement_not_prepared".to_string())
+        )
+    };
+
+    let rj = match db_svc.cql_statement(
         i_statement,
         query_data,
-        i_paging
+        i_paging,
+        trace
     ).await {
         Ok(v) => v,
         Err(e) => return Err(
@@ -45,7 +148,7 @@ async fn execute_single_statement(i_statement: String, i_paging: String, i_query
 
 }
 
-fn cast_json_value_to_scylla_value(i_json_value: &serde_json::Value, i_scylla_value_type: String, i_logging_str: String) -> Result<CqlValue, serde_json::Value> {
+pub fn cast_json_value_to_scylla_value(i_json_value: &serde_json::Value, i_scylla_value_type: String, i_logging_str: String) -> Result<CqlValue, serde_json::Value> {
 
     let r_cql_value_result = match &*i_scylla_value_type {
         "Boolean" => convert_json_value_to_boolean(i_json_value, &i_logging_str),
@@ -55,7 +158,7 @@ fn cast_json_value_to_scylla_value(i_json_value: &serde_json::Value, i_scylla_va
         "Float" => convert_json_value_to_float(i_json_value, &i_logging_str),
         "Int" => convert_json_value_to_int(i_json_value, &i_logging_str),
         "BigInt" => convert_json_value_to_bigint(i_json_value, &i_logging_str),
-        "Text" => convert_json_value_to_text_as_str(i_json_value),
+        "Text" => convert_json_value_to_text_as_str(i_json_value, &i_logging_str),
         "Map" => convert_json_value_to_text(i_json_value),
         "SmallInt" => convert_json_value_to_smallint(i_json_value, &i_logging_str),
         "TinyInt" => convert_json_value_to_tinyint(i_json_value, &i_logging_str),
@@ -87,7 +190,7 @@ fn cast_json_value_to_scylla_value(i_json_value: &serde_json::Value, i_scylla_va
 
     let r_cql_value = match r_cql_value_result {
         Ok(v) => {
-            println!("{}", &i_logging_str);
+            debug!("{}", &i_logging_str);
             v
         },
         Err(e) => return Err(e)
@@ -209,8 +312,13 @@ fn convert_json_value_to_text(i_json_value: &serde_json::Value) -> Result<CqlVal
     Ok(scylla::frame::response::result::CqlValue::Text(r_value))
 }
 
-fn convert_json_value_to_text_as_str(i_json_value: &serde_json::Value) -> Result<CqlValue, serde_json::Value> {
-    let r_value: String = i_json_value.as_str().unwrap().to_string();
+fn convert_json_value_to_text_as_str(i_json_value: &serde_json::Value, i_logging_str: &String) -> Result<CqlValue, serde_json::Value> {
+    let r_value: String = match i_json_value.as_str() {
+        Some(x) => x.to_string(),
+        None => return Err(
+            erfn(500, i_logging_str.to_string(), "value_is_not_a_string".to_string(), "value_is_not_a_string".to_string())
+        )
+    };
     Ok(scylla::frame::response::result::CqlValue::Text(r_value))
 }
 
@@ -254,8 +362,87 @@ fn convert_json_value_to_empty(i_json_value: &serde_json::Value, i_logging_str:
     Ok(scylla::frame::response::result::CqlValue::Empty)
 }
 
+fn check_permissions(i_statement_id: &str, identity: &Identity, state: &AppState) -> Result<(), serde_json::Value> {
+    let attributes = match state.db_svc.p_queries_attributes.get(i_statement_id) {
+        Some(v) => v,
+        None => return Err(
+            erfn(400, format!("statement {} not found", i_statement_id), "unknown_statement_id".to_string(), "unknown_statement_id".to_string())
+        )
+    };
+    let tags: Vec<String> = attributes["tags"]
+        .as_array()
+        .map(|v| v.iter().filter_map(|t| t.as_str().map(|s| s.to_string())).collect())
+        .unwrap_or_default();
+    if !identity.can_execute(i_statement_id, &tags) {
+        return Err(
+            erfn(403, format!("statement {} not allowed for {}", i_statement_id, identity.subject), "statement_not_allowed".to_string(), "statement_not_allowed".to_string())
+        )
+    };
+    let is_query = attributes["is_query"].as_bool().unwrap_or(false);
+    if identity.permissions.read_only && !is_query {
+        return Err(
+            erfn(403, format!("{} is read only", identity.subject), "identity_is_read_only".to_string(), "identity_is_read_only".to_string())
+        )
+    };
+    Ok(())
+}
+
+fn authorize_operation(lline_d: &serde_json::Value, identity: &Identity, state: &AppState) -> Result<(), serde_json::Value> {
+    check_permissions(lline_d["statement_id"].as_str().unwrap(), identity, state)?;
+    if lline_d["trace"].as_bool().unwrap_or(false) && !identity.permissions.allow_trace {
+        return Err(
+            erfn(403, format!("tracing not allowed for {}", identity.subject), "trace_not_allowed".to_string(), "trace_not_allowed".to_string())
+        )
+    };
+    Ok(())
+}
+
+/// Audit record and metrics of an operation refused before the request ran, returns the error.
+fn reject_operation(lline_d: &serde_json::Value, e: serde_json::Value, identity: &Identity, request_id: &str, state: &AppState) -> serde_json::Value {
+    let statement_id = lline_d["statement_id"].as_str().unwrap();
+    let started = Instant::now();
+    let result = Err(e.clone());
+    let attributes = state.db_svc.p_queries_attributes.get(statement_id);
+    state.auditor.record(AuditRecord::new(
+        identity,
+        "execute_statement",
+        statement_id,
+        None,
+        redact_parameters(attributes, lline_d["query_data"].as_array().unwrap()),
+        started,
+        &result
+    ).with_request_id(request_id));
+    let statement_label = if attributes.is_some() {
+        statement_id
+    } else {
+        UNKNOWN_STATEMENT_LABEL
+    };
+    state.metrics.observe_operation("execute_statement", statement_label, started, &result);
+    e
+}
+
+async fn execute_operation(lline_d: &serde_json::Value, identity: &Identity, state: &AppState) -> Result<(serde_json::Value, ExecutionInfo), serde_json::Value> {
+    let trace = lline_d["trace"].as_bool().unwrap_or(false);
+    execute_single_statement(
+        lline_d["statement_id"].as_str().unwrap().to_string(),
+        lline_d["paging"].as_str().unwrap().to_string(),
+        lline_d["query_data"].as_array().unwrap(),
+        trace,
+        identity,
+        state
+    ).await
+}
+
 async fn main_logic(request: HttpRequest, body: web::Bytes, state: &AppState) -> Result<serde_json::Value, serde_json::Value> {
 
+    if state.shutdown.is_draining() {
+        return Err(draining_error())
+    };
+
+    let identity = Identity::from_request(&request);
+    let request_id = RequestId::from_request(&request);
+    let rate_limit_key = rate_limit::identity_key(&identity, request.peer_addr());
+
     let oj: serde_json::Value = match init_input(request, body) {
         Ok(v) => match check_input_data(v) {
             Ok(w) => w,
@@ -264,19 +451,91 @@ async fn main_logic(request: HttpRequest, body: web::Bytes, state: &AppState) ->
         Err(e) => return Err(e)
     };
 
-    let mut rj = json!({});
-
+    // Every operation is checked before the first one runs, a request refused on its second
+    // operation must not have applied the first one.
+    let mut operations = vec![];
     for lline in oj["body"]["operation"].as_array().unwrap() {
         let lline_d = match check_input_data_single_line(lline) {
             Ok(v) => v,
             Err(e) => return Err(e)
         };
-        rj[lline_d["statement_id"].as_str().unwrap().to_string()] = match execute_single_statement(
-            lline_d["statement_id"].as_str().unwrap().to_string(),
-            lline_d["paging"].as_str().unwrap().to_string(),
-            lline_d["query_data"].as_array().unwrap(),
-            state
-        ).await {
+        if let Err(e) = authorize_operation(lline_d, &identity, state) {
+            return Err(reject_operation(lline_d, e, &identity, &request_id, state))
+        };
+        operations.push(lline_d);
+    }
+
+    // Read only mode refuses the whole request when one of its operations writes.
+    if state.read_only.is_enabled() {
+        let first_write = operations.iter().find(|lline_d| {
+            !state.db_svc.p_queries_attributes[lline_d["statement_id"].as_str().unwrap()]["is_query"].as_bool().unwrap_or(false)
+        });
+        if let Some(lline_d) = first_write {
+            return Err(reject_operation(lline_d, read_only_error(), &identity, &request_id, state))
+        };
+    };
+
+    let statement_ids: Vec<&str> = operations.iter().map(|lline_d| lline_d["statement_id"].as_str().unwrap()).collect();
+    if let Err(e) = state.rate_limiter.check(&rate_limit_key, &statement_ids) {
+        return Err(e.to_error())
+    };
+
+    let mut rj = json!({});
+
+    for lline_d in operations {
+        let statement_id = lline_d["statement_id"].as_str().unwrap();
+        let started = Instant::now();
+        let in_flight = state.metrics.operation_started();
+        let span = info_span!(
+            "operation",
+            endpoint = "execute_statement",
+            statement_id = %statement_id,
+            otel.status_code = field::Empty,
+            error.type = field::Empty
+        );
+        let outcome = execute_operation(lline_d, &identity, state).instrument(span.clone()).await;
+        let latency = started.elapsed();
+        telemetry::record_result(&span, &outcome);
+        drop(in_flight);
+        let attributes = state.db_svc.p_queries_attributes.get(statement_id);
+        let parameters = redact_parameters(attributes, lline_d["query_data"].as_array().unwrap());
+        let statement_threshold = attributes.and_then(|a| a["slow_query_threshold_ms"].as_u64());
+        if let Some(threshold) = state.slow_queries.exceeded(statement_threshold, latency) {
+            let mut slow_query = SlowQuery::new(
+                &request_id,
+                "execute_statement",
+                statement_id,
+                slow_query::fingerprint(&parameters),
+                latency,
+                threshold,
+                &outcome
+            );
+            slow_query.coordinator = match (&outcome, state.statement_cluster(statement_id)) {
+                (Ok((_, execution)), Ok(db_svc)) => db_svc.estimate_coordinator(statement_id, execution),
+                _ => None
+            };
+            state.slow_queries.record(slow_query);
+        }
+        let result = match outcome {
+            Ok((v, execution)) => Ok(attach_tracing(v, &execution, lline_d["trace_events"].as_bool().unwrap_or(false), state.statement_cluster(statement_id).unwrap_or(&state.db_svc)).await),
+            Err(e) => Err(e)
+        };
+        state.auditor.record(AuditRecord::new(
+            &identity,
+            "execute_statement",
+            statement_id,
+            None,
+            parameters,
+            started,
+            &result
+        ).with_request_id(&request_id));
+        let statement_label = if state.db_svc.p_queries_attributes.contains_key(statement_id) {
+            statement_id
+        } else {
+            UNKNOWN_STATEMENT_LABEL
+        };
+        state.metrics.observe_operation("execute_statement", statement_label, started, &result);
+        rj[statement_id.to_string()] = match result {
             Ok(v) => v,
             Err(e) => return Err(e)
         };
@@ -330,25 +589,106 @@ pub fn check_input_data_single_line(oj: &serde_json::Value) -> Result<&serde_jso
             erfn(400, "paging_must_be_a_string".to_string(), "paging_must_be_a_string".to_string(), "paging_must_be_a_string".to_string())
         )
     };
+    if !oj["trace"].is_null() && !oj["trace"].is_boolean() {
+        return Err(
+            erfn(400, "trace_must_be_a_boolean".to_string(), "trace_must_be_a_boolean".to_string(), "trace_must_be_a_boolean".to_string())
+        )
+    };
+    if !oj["trace_events"].is_null() && !oj["trace_events"].is_boolean() {
+        return Err(
+            erfn(400, "trace_events_must_be_a_boolean".to_string(), "trace_events_must_be_a_boolean".to_string(), "trace_events_must_be_a_boolean".to_string())
+        )
+    };
     Ok(oj)
 }
 
 pub async fn index(request: HttpRequest, body: web::Bytes, state: web::Data<AppState>) -> Result<HttpResponse, Error> {
     let state: &AppState = &state;
+    let request_id = RequestId::from_request(&request);
     let oj: serde_json::Value = match main_logic(request, body, state).await {
         Ok(v) => v,
-        Err(e) => {
-            let ret_status_code: u16 = match decode_status_code(&e["status_code"]) {
-                Ok(n) => n,
-                Err(_e) => 500
-            };
-            println!("ERRORE - RESPONSE {}", e.to_string());
-            return Ok(HttpResponse::build(StatusCode::from_u16(ret_status_code).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR))
-            .insert_header(ContentType::json())
-            .body(e.to_string()))
+        Err(mut e) => {
+            warn!("Error response {}", e);
+            e["request_id"] = json!(request_id);
+            return Ok(error_response(&e))
         }
     };
+    debug!(response = %state.redactor.redact_response(&oj, &state.db_svc.p_queries_attributes), "Response sent");
     Ok(HttpResponse::Ok()
         .content_type("application/json")
         .body(oj.to_string()))
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::Permissions;
+
+    fn attributes(i_casting: serde_json::Value, i_identity_binding: serde_json::Value) -> HashMap<String, serde_json::Value> {
+        HashMap::from([
+            ("casting".to_string(), i_casting),
+            ("identity_binding".to_string(), i_identity_binding),
+        ])
+    }
+
+    fn identity(i_attributes: &[(&str, serde_json::Value)]) -> Identity {
+        Identity {
+            subject: "alice".to_string(),
+            method: "jwt".to_string(),
+            permissions: Permissions::default(),
+            attributes: i_attributes.iter().map(|(k, v)| (k.to_string(), v.clone())).collect(),
+        }
+    }
+
+    fn error_code(i_error: serde_json::Value) -> (u64, String) {
+        (i_error["status_code"].as_u64().unwrap(), i_error["error_message"].as_str().unwrap().to_string())
+    }
+
+    #[test]
+    fn identity_positions_are_filled_around_client_values() {
+        let statement = attributes(json!({"0": "Text", "1": "Int", "2": "Text"}), json!({"0": "claim:tenant"}));
+        let alice = identity(&[("claim:tenant", json!("acme"))]);
+        let bound = bind_query_data("GET_ORDER", &statement, &[json!(42), json!("paid")], &alice).unwrap();
+        assert_eq!(bound, vec![json!("acme"), json!(42), json!("paid")]);
+    }
+
+    #[test]
+    fn missing_identity_attribute_is_refused() {
+        let statement = attributes(json!({"0": "Text", "1": "Int"}), json!({"0": "claim:tenant"}));
+        let e = bind_query_data("GET_ORDER", &statement, &[json!(42)], &identity(&[])).unwrap_err();
+        assert_eq!(error_code(e), (403, "identity_attribute_missing".to_string()));
+    }
+
+    #[test]
+    fn identity_attribute_of_the_wrong_type_is_refused() {
+        let statement = attributes(json!({"0": "Int"}), json!({"0": "claim:tenant"}));
+        let e = bind_query_data("GET_ORDER", &statement, &[], &identity(&[("claim:tenant", json!("acme"))])).unwrap_err();
+        assert_eq!(error_code(e), (500, "identity_attribute_type_mismatch".to_string()));
+    }
+
+    #[test]
+    fn too_many_client_values_are_refused() {
+        let statement = attributes(json!({"0": "Text", "1": "Int"}), json!({"0": "claim:tenant"}));
+        let alice = identity(&[("claim:tenant", json!("acme"))]);
+        let e = bind_query_data("GET_ORDER", &statement, &[json!(42), json!(43)], &alice).unwrap_err();
+        assert_eq!(error_code(e), (400, "query_data_too_long".to_string()));
+        let e = bind_query_data("GET_ORDER", &attributes(json!({}), json!({})), &[json!(1)], &alice).unwrap_err();
+        assert_eq!(error_code(e), (400, "query_data_too_long".to_string()));
+    }
+
+    #[test]
+    fn too_few_client_values_are_refused() {
+        let statement = attributes(json!({"0": "Text", "1": "Int", "2": "Text"}), json!({"0": "claim:tenant"}));
+        let alice = identity(&[("claim:tenant", json!("acme"))]);
+        let e = bind_query_data("GET_ORDER", &statement, &[json!(42)], &alice).unwrap_err();
+        assert_eq!(error_code(e), (400, "query_data_too_short".to_string()));
+    }
+
+    #[test]
+    fn client_value_of_the_wrong_type_is_refused() {
+        let statement = attributes(json!({"0": "Int", "1": "Boolean"}), json!({}));
+        let e = bind_query_data("GET_ORDER", &statement, &[json!("42"), json!(true)], &identity(&[])).unwrap_err();
+        assert_eq!(error_code(e), (400, "value_type_mismatch".to_string()));
+        let e = bind_query_data("GET_ORDER", &statement, &[json!(42), json!(1)], &identity(&[])).unwrap_err();
+        assert_eq!(error_code(e), (400, "value_type_mismatch".to_string()));
+    }
+}
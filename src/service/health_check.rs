@@ -13,4 +13,43 @@ pub async fn index(_request: HttpRequest, _body: web::Bytes, _state: web::Data<A
         .content_type("application/json").body(json!({
             "is_success": true
         }).to_string()))
-}
\ No newline at end of file
+}
+/// The process answers, nothing else is checked so a cluster outage doesn't get the proxy restarted.
+pub async fn liveness() -> Result<HttpResponse, Error> {
+    Ok(HttpResponse::Ok()
+        .content_type("application/json").body(json!({
+            "status": "alive"
+        }).to_string()))
+}
+
+pub async fn readiness(state: web::Data<AppState>) -> Result<HttpResponse, Error> {
+    if state.shutdown.is_draining() {
+        return Ok(HttpResponse::ServiceUnavailable()
+            .content_type("application/json").body(json!({
+                "status": "draining"
+            }).to_string()))
+    }
+    let mut readiness = state.health.readiness(&state.clusters).await;
+    if let Some(certificate) = &state.certificate {
+        readiness.details["certificate"] = certificate.describe();
+    }
+    let mut response = if readiness.ready {
+        HttpResponse::Ok()
+    } else {
+        HttpResponse::ServiceUnavailable()
+    };
+    Ok(response
+        .content_type("application/json")
+        .body(readiness.details.to_string()))
+}
+
+/// Passes for good once readiness has passed, until then it runs the readiness checks.
+pub async fn startup(state: web::Data<AppState>) -> Result<HttpResponse, Error> {
+    if state.health.has_started() {
+        return Ok(HttpResponse::Ok()
+            .content_type("application/json").body(json!({
+                "status": "started"
+            }).to_string()))
+    }
+    readiness(state).await
+}
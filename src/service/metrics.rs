@@ -0,0 +1,20 @@
+use crate::common::{erfn, error_response, AppState};
+use actix_web::{
+    web, 
+    Error, 
+    HttpRequest, 
+    HttpResponse
+};
+use anyhow::Result;
+
+pub async fn index(_request: HttpRequest, state: web::Data<AppState>) -> Result<HttpResponse, Error> {
+    let body = match state.metrics.render(&state) {
+        Ok(v) => v,
+        Err(e) => return Ok(error_response(
+            &erfn(500, e.to_string(), "metrics_error".to_string(), "metrics_error".to_string())
+        ))
+    };
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(body))
+}
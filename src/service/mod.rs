@@ -1,3 +1,5 @@
+pub mod admin;
 pub mod execute_statement;
 pub mod direct_statement;
-pub mod health_check;
\ No newline at end of file
+pub mod health_check;
+pub mod metrics;
\ No newline at end of file
@@ -0,0 +1,67 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use actix_web::rt::signal::{ctrl_c, unix::{signal, SignalKind}};
+use futures_util::future::{select, Either};
+use tokio::sync::Notify;
+use tracing::{error, warn};
+
+/// Drain state of the proxy. Once requested, readiness fails, new operations are answered
+/// with a 503 and the listeners stop, giving in-flight requests the grace period to finish.
+pub struct Shutdown {
+    draining: AtomicBool,
+    requested: Notify,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Shutdown {
+    pub fn new() -> Shutdown {
+        Shutdown {
+            draining: AtomicBool::new(false),
+            requested: Notify::new(),
+        }
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    /// Only the first request starts the drain, the next ones are no-ops.
+    pub fn request(&self, i_reason: &str) {
+        if !self.draining.swap(true, Ordering::SeqCst) {
+            warn!("Draining, reason: {}", i_reason);
+            self.requested.notify_one();
+        }
+    }
+
+    /// Resolves once a drain has been requested.
+    pub async fn requested(&self) {
+        if self.is_draining() {
+            return
+        }
+        self.requested.notified().await
+    }
+
+    /// Starts the drain on SIGTERM or Ctrl-C, instead of the actix default that stops the listeners
+    /// without failing readiness first.
+    pub fn watch_signals(shutdown: Arc<Shutdown>) {
+        actix_web::rt::spawn(async move {
+            let mut sigterm = match signal(SignalKind::terminate()) {
+                Ok(v) => v,
+                Err(e) => {
+                    error!("SIGTERM handler not installed: {}", e);
+                    return
+                }
+            };
+            let reason = match select(Box::pin(sigterm.recv()), Box::pin(ctrl_c())).await {
+                Either::Left(_) => "SIGTERM",
+                Either::Right(_) => "SIGINT"
+            };
+            shutdown.request(reason);
+        });
+    }
+}
@@ -0,0 +1,108 @@
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::warn;
+use crate::common::{sha256_hex, ExecutionInfo};
+
+/// One operation that took longer than its threshold.
+#[derive(Debug, Clone, Serialize)]
+pub struct SlowQuery {
+    pub timestamp_ms: u128,
+    pub request_id: String,
+    pub endpoint: String,
+    pub statement_id: String,
+    pub parameters_fingerprint: String,
+    pub latency_ms: f64,
+    pub threshold_ms: u64,
+    pub outcome: String,
+    pub rows_returned: u64,
+    pub page_size: Option<u64>,
+    pub coordinator: Option<String>,
+    pub tracing_session_id: Option<String>,
+}
+
+impl SlowQuery {
+    pub fn new(
+        i_request_id: &str,
+        i_endpoint: &str,
+        i_statement_id: &str,
+        i_parameters_fingerprint: String,
+        i_latency: Duration,
+        i_threshold: Duration,
+        i_result: &Result<(serde_json::Value, ExecutionInfo), serde_json::Value>
+    ) -> SlowQuery {
+        let (outcome, rows_returned, page_size, tracing_id) = match i_result {
+            Ok((v, execution)) => ("success", v["records_number"].as_u64().unwrap_or(0), execution.page_size, execution.tracing_id),
+            Err(_) => ("error", 0, None, None)
+        };
+        SlowQuery {
+            timestamp_ms: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis()).unwrap_or(0),
+            request_id: i_request_id.to_string(),
+            endpoint: i_endpoint.to_string(),
+            statement_id: i_statement_id.to_string(),
+            parameters_fingerprint: i_parameters_fingerprint,
+            latency_ms: i_latency.as_secs_f64() * 1000.0,
+            threshold_ms: i_threshold.as_millis() as u64,
+            outcome: outcome.to_string(),
+            rows_returned,
+            page_size,
+            coordinator: None,
+            tracing_session_id: tracing_id.map(|id| id.to_string()),
+        }
+    }
+}
+
+/// Short hash of the (already redacted) parameters, equal parameters give equal fingerprints.
+pub fn fingerprint(i_parameters: &[serde_json::Value]) -> String {
+    sha256_hex(&serde_json::Value::from(i_parameters.to_vec()).to_string())[..16].to_string()
+}
+
+/// Logs the slow operations and keeps the last `capacity` ones for the admin endpoint.
+pub struct SlowQueryLog {
+    threshold: Option<Duration>,
+    capacity: usize,
+    entries: Mutex<VecDeque<SlowQuery>>,
+}
+
+impl SlowQueryLog {
+    /// `threshold_ms` is the global threshold, without it only statements with their own
+    /// `slow_query_threshold_ms` are checked.
+    pub fn new(threshold_ms: Option<u64>, capacity: usize) -> SlowQueryLog {
+        SlowQueryLog {
+            threshold: threshold_ms.map(Duration::from_millis),
+            capacity,
+            entries: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    /// Returns the threshold that `latency` went over, if any.
+    pub fn exceeded(&self, statement_threshold_ms: Option<u64>, latency: Duration) -> Option<Duration> {
+        let threshold = match statement_threshold_ms {
+            Some(ms) => Duration::from_millis(ms),
+            None => self.threshold?
+        };
+        if latency >= threshold {
+            Some(threshold)
+        } else {
+            None
+        }
+    }
+
+    pub fn record(&self, entry: SlowQuery) {
+        warn!(target: "slow_query", entry = %serde_json::to_string(&entry).unwrap_or_default(), "Slow query");
+        if self.capacity == 0 {
+            return
+        }
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() == self.capacity {
+            entries.pop_front();
+        }
+        entries.push_back(entry);
+    }
+
+    /// Most recent first.
+    pub fn recent(&self, limit: usize) -> Vec<SlowQuery> {
+        self.entries.lock().unwrap().iter().rev().take(limit).cloned().collect()
+    }
+}
@@ -0,0 +1,135 @@
+use actix_web::{
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    http::header::HeaderMap,
+    middleware::Next,
+    Error,
+    HttpMessage
+};
+use opentelemetry::{global, propagation::Extractor, trace::TracerProvider as _, KeyValue};
+use opentelemetry_sdk::{propagation::TraceContextPropagator, runtime, trace::{self as sdktrace, Sampler}, Resource};
+use opentelemetry_otlp::WithExportConfig;
+use tracing::{field, Instrument, Span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use std::fs::OpenOptions;
+use crate::request_id::RequestId;
+
+pub const EXPORTER_NONE: &str = "none";
+pub const EXPORTER_OTLP: &str = "otlp";
+pub const EXPORTER_STDOUT: &str = "stdout";
+pub const EXPORTER_FILE: &str = "file";
+
+pub struct TelemetrySettings {
+    pub exporter: String,
+    pub endpoint: Option<String>,
+    pub file: Option<String>,
+    pub service_name: String,
+    pub sample_ratio: f64,
+}
+
+/// Builds the span exporter pipeline and registers it as the global tracer provider.
+/// Returns `None` when the exporter is `none`, spans are then only used by the log output.
+pub fn init_tracer_provider(settings: &TelemetrySettings) -> Result<Option<sdktrace::Tracer>, anyhow::Error> {
+
+    let trace_config = sdktrace::config()
+        .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(settings.sample_ratio))))
+        .with_resource(Resource::new(vec![KeyValue::new("service.name", settings.service_name.clone())]));
+
+    let builder = sdktrace::TracerProvider::builder().with_config(trace_config);
+
+    let provider = match settings.exporter.as_str() {
+        EXPORTER_NONE | "" => return Ok(None),
+        EXPORTER_OTLP => {
+            let mut exporter = opentelemetry_otlp::new_exporter().http();
+            if let Some(endpoint) = &settings.endpoint {
+                exporter = exporter.with_endpoint(endpoint);
+            }
+            builder
+                .with_batch_exporter(exporter.build_span_exporter()?, runtime::TokioCurrentThread)
+                .build()
+        },
+        EXPORTER_STDOUT => builder
+            .with_batch_exporter(opentelemetry_stdout::SpanExporter::default(), runtime::TokioCurrentThread)
+            .build(),
+        EXPORTER_FILE => {
+            let path = match &settings.file {
+                Some(v) => v,
+                None => return Err(anyhow::anyhow!("the file exporter needs OTEL_FILE"))
+            };
+            let file = OpenOptions::new().create(true).append(true).open(path)?;
+            builder
+                .with_batch_exporter(
+                    opentelemetry_stdout::SpanExporter::builder().with_writer(file).build(),
+                    runtime::TokioCurrentThread
+                )
+                .build()
+        },
+        other => return Err(anyhow::anyhow!("unknown trace exporter {}, expected one of none, otlp, stdout, file", other))
+    };
+
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    let tracer = provider.tracer("yaw_dbproxy");
+    global::set_tracer_provider(provider);
+
+    Ok(Some(tracer))
+}
+
+/// Flushes the spans still waiting in the batch exporter.
+pub fn shutdown() {
+    global::shutdown_tracer_provider();
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|v| v.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|k| k.as_str()).collect()
+    }
+}
+
+/// Opens the `http_request` span, continuing the trace of the incoming `traceparent` header when there is one.
+/// The span carries the request id, so every log line of the request has it.
+pub async fn trace_http(
+    request: ServiceRequest,
+    next: Next<impl MessageBody>
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+
+    let parent = global::get_text_map_propagator(|propagator| propagator.extract(&HeaderExtractor(request.headers())));
+    let route = request.match_pattern().unwrap_or_else(|| "unmatched".to_string());
+    let request_id = request.extensions().get::<RequestId>().map(|r| r.0.clone()).unwrap_or_default();
+
+    let span = tracing::info_span!(
+        "http_request",
+        otel.name = %format!("{} {}", request.method(), route),
+        otel.kind = "server",
+        otel.status_code = field::Empty,
+        http.request.method = %request.method(),
+        http.route = %route,
+        url.path = %request.path(),
+        http.response.status_code = field::Empty,
+        request_id = %request_id
+    );
+    span.set_parent(parent);
+
+    let response = next.call(request).instrument(span.clone()).await?;
+
+    let status = response.status();
+    span.record("http.response.status_code", status.as_u16());
+    if status.is_server_error() {
+        span.record("otel.status_code", "ERROR");
+    }
+
+    Ok(response)
+}
+
+/// Marks an `operation` span as failed when the operation returned an `erfn` error.
+pub fn record_result<T>(span: &Span, result: &Result<T, serde_json::Value>) {
+    if let Err(e) = result {
+        span.record("otel.status_code", "ERROR");
+        span.record("error.type", e["custom_error_message"].as_str().unwrap_or("unknown"));
+    }
+}
@@ -0,0 +1,117 @@
+use openssl::asn1::Asn1Time;
+use openssl::ssl::{SslAcceptorBuilder, SslContext};
+use serde_json::json;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{error, info};
+use crate::app_config::{load_openssl_config, Config};
+
+/// HTTPS certificate of the listeners, reloaded when `TLS_CERT_FILE` or `TLS_KEY_FILE` change.
+/// New handshakes get the current certificate, open connections keep the one they started with.
+pub struct ServerCertificate {
+    config: Config,
+    context: RwLock<SslContext>,
+    not_after: AtomicI64,
+    reloads_succeeded: AtomicU64,
+    reloads_failed: AtomicU64,
+}
+
+/// Context built with the HTTPS settings and expiry of its certificate, in seconds since the epoch.
+fn build_context(config: &Config) -> Result<(SslContext, i64), anyhow::Error> {
+    let context = load_openssl_config(config)?.build().into_context();
+    let certificate = match context.certificate() {
+        Some(v) => v,
+        None => return Err(anyhow::anyhow!("no certificate in {}", config.tls_cert_file))
+    };
+    let diff = Asn1Time::from_unix(0)?.diff(certificate.not_after())?;
+    Ok((context, diff.days as i64 * 86400 + diff.secs as i64))
+}
+
+fn modified(i_path: &str) -> Option<SystemTime> {
+    std::fs::metadata(Path::new(i_path)).and_then(|m| m.modified()).ok()
+}
+
+fn now_seconds() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or(0)
+}
+
+impl ServerCertificate {
+    pub fn load(config: &Config) -> Result<Arc<ServerCertificate>, anyhow::Error> {
+        let (context, not_after) = build_context(config)?;
+        Ok(Arc::new(ServerCertificate {
+            config: config.clone(),
+            context: RwLock::new(context),
+            not_after: AtomicI64::new(not_after),
+            reloads_succeeded: AtomicU64::new(0),
+            reloads_failed: AtomicU64::new(0),
+        }))
+    }
+
+    /// Acceptor for one HTTPS listener, it switches every handshake to the current certificate.
+    pub fn acceptor(certificate: &Arc<ServerCertificate>) -> Result<SslAcceptorBuilder, anyhow::Error> {
+        let mut builder = load_openssl_config(&certificate.config)?;
+        let current = certificate.clone();
+        builder.set_servername_callback(move |ssl, _alert| {
+            let context = current.context.read().unwrap();
+            // Without this the handshake goes on with the certificate loaded at startup.
+            let _ = ssl.set_ssl_context(&context);
+            Ok(())
+        });
+        Ok(builder)
+    }
+
+    pub fn not_after(&self) -> i64 {
+        self.not_after.load(Ordering::SeqCst)
+    }
+
+    /// Successful and failed reloads.
+    pub fn reloads(&self) -> (u64, u64) {
+        (self.reloads_succeeded.load(Ordering::SeqCst), self.reloads_failed.load(Ordering::SeqCst))
+    }
+
+    /// Expiry of the certificate in use, for the health output.
+    pub fn describe(&self) -> serde_json::Value {
+        let not_after = self.not_after();
+        json!({
+            "file": self.config.tls_cert_file,
+            "not_after": not_after,
+            "expires_in_seconds": not_after - now_seconds()
+        })
+    }
+
+    /// A certificate that can't be loaded, for example one whose key is not written yet,
+    /// keeps the previous one in place until the files change again.
+    fn reload(&self) {
+        match build_context(&self.config) {
+            Ok((context, not_after)) => {
+                *self.context.write().unwrap() = context;
+                self.not_after.store(not_after, Ordering::SeqCst);
+                self.reloads_succeeded.fetch_add(1, Ordering::SeqCst);
+                info!("Reloaded HTTPS certificate {}, expires at {}", self.config.tls_cert_file, not_after);
+            },
+            Err(e) => {
+                self.reloads_failed.fetch_add(1, Ordering::SeqCst);
+                error!("HTTPS certificate {} not reloaded: {:#}", self.config.tls_cert_file, e);
+            }
+        }
+    }
+
+    pub fn watch(certificate: Arc<ServerCertificate>, i_poll_interval: Duration) {
+        info!("Watching HTTPS certificate {} and key {}", certificate.config.tls_cert_file, certificate.config.tls_key_file);
+        let files = |c: &ServerCertificate| (modified(&c.config.tls_cert_file), modified(&c.config.tls_key_file));
+        let mut last_seen = files(&certificate);
+        actix_web::rt::spawn(async move {
+            let mut interval = actix_web::rt::time::interval(i_poll_interval);
+            loop {
+                interval.tick().await;
+                let current = files(&certificate);
+                if current != last_seen {
+                    certificate.reload();
+                    last_seen = current;
+                }
+            }
+        });
+    }
+}
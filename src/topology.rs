@@ -0,0 +1,148 @@
+use serde_json::json;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use actix_web::rt::{net::TcpStream, time::timeout};
+use futures_util::future::join_all;
+use scylla::frame::response::result::CqlValue;
+use scylla::routing::Token;
+use scylla::transport::Node;
+use scylla::transport::topology::{CqlType, NativeType};
+use crate::common::{erfn, ScyllaService};
+use crate::service::execute_statement::cast_json_value_to_scylla_value;
+
+const DEFAULT_CQL_PORT: u16 = 9042;
+const SEED_CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+
+fn node_json(node: &Arc<Node>, token: Option<Token>) -> serde_json::Value {
+    let sharder = node.sharder();
+    let mut rj = json!({
+        "address": node.address.to_string(),
+        "datacenter": node.datacenter,
+        "rack": node.rack,
+        "is_down": node.is_down(),
+        "enabled": node.is_enabled(),
+        "shards": sharder.as_ref().map(|s| s.nr_shards.get())
+    });
+    if let Some(token) = token {
+        rj["shard"] = json!(sharder.map(|s| s.shard_of(token)));
+    }
+    rj
+}
+
+/// `host`, `host:port`, `ip` or `ip:port` as given in the configuration.
+fn seed_address(i_seed: &str) -> String {
+    if let Ok(ip) = i_seed.parse::<IpAddr>() {
+        return SocketAddr::new(ip, DEFAULT_CQL_PORT).to_string()
+    }
+    match i_seed.rsplit_once(':') {
+        Some((_, port)) if port.parse::<u16>().is_ok() => i_seed.to_string(),
+        _ => format!("{}:{}", i_seed, DEFAULT_CQL_PORT)
+    }
+}
+
+async fn check_seed(i_seed: &str) -> serde_json::Value {
+    let address = seed_address(i_seed);
+    let error = match timeout(SEED_CONNECT_TIMEOUT, TcpStream::connect(&address)).await {
+        Ok(Ok(_)) => None,
+        Ok(Err(e)) => Some(e.to_string()),
+        Err(_) => Some(format!("no answer within {} s", SEED_CONNECT_TIMEOUT.as_secs()))
+    };
+    json!({
+        "seed": i_seed,
+        "reachable": error.is_none(),
+        "error": error
+    })
+}
+
+/// What the driver knows about one cluster, plus a TCP check of the configured contact points.
+pub async fn describe(db_svc: &ScyllaService) -> serde_json::Value {
+    let cluster_data = db_svc.db_session.get_cluster_data();
+    let nodes: Vec<serde_json::Value> = cluster_data
+        .get_nodes_info()
+        .iter()
+        .map(|node| node_json(node, None))
+        .collect();
+    let schema_agreement = match db_svc.db_session.check_schema_agreement().await {
+        Ok(v) => json!(v),
+        Err(e) => json!({ "error": e.to_string() })
+    };
+    let seeds = join_all(db_svc.contact_points.iter().map(|seed| check_seed(seed))).await;
+
+    json!({
+        "cluster": db_svc.name,
+        "local_datacenter": db_svc.datacenter,
+        "nodes": nodes,
+        "schema_agreement": schema_agreement,
+        "seeds": seeds
+    })
+}
+
+/// Casting name of `execute_statement` for the partition key column types it can convert.
+fn casting_of(i_type: &CqlType) -> Option<&'static str> {
+    match i_type {
+        CqlType::Native(NativeType::Ascii) | CqlType::Native(NativeType::Text) => Some("Text"),
+        CqlType::Native(NativeType::Boolean) => Some("Boolean"),
+        CqlType::Native(NativeType::Decimal) => Some("Decimal"),
+        CqlType::Native(NativeType::Double) => Some("Double"),
+        CqlType::Native(NativeType::Float) => Some("Float"),
+        CqlType::Native(NativeType::Int) => Some("Int"),
+        CqlType::Native(NativeType::BigInt) => Some("BigInt"),
+        CqlType::Native(NativeType::SmallInt) => Some("SmallInt"),
+        CqlType::Native(NativeType::TinyInt) => Some("TinyInt"),
+        _ => None
+    }
+}
+
+/// Token and replicas of a partition, like `nodetool getendpoints`.
+/// `i_partition_key` holds the partition key column values in the table order.
+pub fn locate_partition(db_svc: &ScyllaService, i_keyspace: &str, i_table: &str, i_partition_key: &[serde_json::Value]) -> Result<serde_json::Value, serde_json::Value> {
+    let cluster_data = db_svc.db_session.get_cluster_data();
+    let table = match cluster_data.get_keyspace_info().get(i_keyspace).and_then(|k| k.tables.get(i_table)) {
+        Some(v) => v,
+        None => return Err(
+            erfn(404, format!("table {}.{} not found", i_keyspace, i_table), "table_not_found".to_string(), "table_not_found".to_string())
+        )
+    };
+    if table.partition_key.len() != i_partition_key.len() {
+        return Err(
+            erfn(400, format!("{}.{} partition key is ({})", i_keyspace, i_table, table.partition_key.join(", ")), "partition_key_length_mismatch".to_string(), "partition_key_length_mismatch".to_string())
+        )
+    }
+
+    let mut values: Vec<CqlValue> = vec![];
+    for (column, value) in table.partition_key.iter().zip(i_partition_key) {
+        let casting = match table.columns.get(column).and_then(|c| casting_of(&c.type_)) {
+            Some(v) => v,
+            None => return Err(
+                erfn(400, format!("column {} has a type the locator can't convert", column), "partition_key_type_not_supported".to_string(), "partition_key_type_not_supported".to_string())
+            )
+        };
+        if casting == "Text" && !value.is_string() {
+            return Err(
+                erfn(400, format!("column {} needs a string", column), "value_is_not_a_string".to_string(), "value_is_not_a_string".to_string())
+            )
+        }
+        let logging_str = format!("partition key column: {} - casting: {}", column, casting);
+        values.push(cast_json_value_to_scylla_value(value, casting.to_string(), logging_str)?);
+    }
+
+    let token = match cluster_data.compute_token(i_keyspace, i_table, &values) {
+        Ok(v) => v,
+        Err(e) => return Err(
+            erfn(400, e.to_string(), "token_not_computable".to_string(), "token_not_computable".to_string())
+        )
+    };
+    let replicas: Vec<serde_json::Value> = cluster_data
+        .get_token_endpoints(i_keyspace, token)
+        .iter()
+        .map(|node| node_json(node, Some(token)))
+        .collect();
+
+    Ok(json!({
+        "keyspace": i_keyspace,
+        "table": i_table,
+        "token": token.value,
+        "replicas": replicas
+    }))
+}